@@ -1,4 +1,4 @@
-use std::{collections::VecDeque, os::raw::c_int, sync::Arc};
+use std::{collections::VecDeque, os::raw::c_int, ptr, sync::Arc};
 
 use bytemuck;
 use ctor::ctor;
@@ -9,29 +9,222 @@ use media_codec::{
     CodecInformation, CodecParameters,
 };
 use media_core::{
-    audio::{AudioFrame, AudioFrameDescriptor, SampleFormat},
+    audio::{AudioFrame, AudioFrameDescriptor, ChannelLayout, SampleFormat},
     error::Error,
     frame::SharedFrame,
     frame_pool::FramePool,
-    invalid_param_error, unsupported_error,
+    invalid_param_error,
+    rational::Rational64,
+    unsupported_error,
     variant::Variant,
     Result,
 };
 
-use crate::{opus_error_string, opus_sys};
+use crate::{header::OpusHead, opus_error_string, opus_sys, sdp::OpusFmtp, vorbis_channel_order};
 
-struct OpusDecoder {
-    decoder: *mut opus_sys::OpusDecoder,
+// Input of a single decode call
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Packet(&'a [u8]),
+    // In-band FEC data of the packet following the lost one
+    Fec(&'a [u8]),
+    Conceal,
+    // Deep REDundancy data, at the given offset in samples before the packet
+    Dred(u32),
+}
+
+struct DredState {
+    decoder: *mut opus_sys::OpusDREDDecoder,
+    dred: *mut opus_sys::OpusDRED,
+}
+
+impl DredState {
+    fn new() -> Result<Self> {
+        let mut ret = 0;
+        let decoder = unsafe { opus_sys::opus_dred_decoder_create(&mut ret) };
+        if decoder.is_null() || ret != opus_sys::OPUS_OK {
+            return Err(Error::CreationFailed(opus_error_string(ret)));
+        }
+
+        let dred = unsafe { opus_sys::opus_dred_alloc(&mut ret) };
+        if dred.is_null() || ret != opus_sys::OPUS_OK {
+            unsafe { opus_sys::opus_dred_decoder_destroy(decoder) };
+            return Err(Error::CreationFailed(opus_error_string(ret)));
+        }
+
+        Ok(DredState {
+            decoder,
+            dred,
+        })
+    }
+
+    // Returns the offsets, in samples before the packet, of the first and last
+    // recoverable samples. The samples closer to the packet than the last one
+    // are not covered
+    fn parse(&mut self, data: &[u8], max_samples: u32, sample_rate: u32) -> (u32, u32) {
+        let mut dred_end = 0;
+        let ret = unsafe {
+            opus_sys::opus_dred_parse(
+                self.decoder,
+                self.dred,
+                data.as_ptr(),
+                data.len() as opus_sys::opus_int32,
+                max_samples as opus_sys::opus_int32,
+                sample_rate as opus_sys::opus_int32,
+                &mut dred_end,
+                0,
+            )
+        };
+
+        if ret <= 0 {
+            return (0, 0);
+        }
+
+        (ret as u32, dred_end.max(0) as u32)
+    }
+}
+
+impl Drop for DredState {
+    fn drop(&mut self) {
+        unsafe {
+            opus_sys::opus_dred_free(self.dred);
+            opus_sys::opus_dred_decoder_destroy(self.decoder);
+        }
+    }
+}
+
+enum RawDecoder {
+    Single(*mut opus_sys::OpusDecoder),
+    Multistream(*mut opus_sys::OpusMSDecoder),
+    Projection(*mut opus_sys::OpusProjectionDecoder),
+}
+
+// A null pointer requests packet loss concealment
+fn packet_ptr(data: &[u8]) -> (*const u8, opus_sys::opus_int32) {
+    if data.is_empty() {
+        (ptr::null(), 0)
+    } else {
+        (data.as_ptr(), data.len() as opus_sys::opus_int32)
+    }
+}
+
+impl RawDecoder {
+    fn ctl(&self, key: i32, value: i32) -> c_int {
+        match *self {
+            RawDecoder::Single(decoder) => unsafe { opus_sys::opus_decoder_ctl(decoder, key, value) },
+            RawDecoder::Multistream(decoder) => unsafe { opus_sys::opus_multistream_decoder_ctl(decoder, key, value) },
+            RawDecoder::Projection(decoder) => unsafe { opus_sys::opus_projection_decoder_ctl(decoder, key, value) },
+        }
+    }
+
+    fn ctl_get(&self, key: i32, value: &mut i32) -> c_int {
+        match *self {
+            RawDecoder::Single(decoder) => unsafe { opus_sys::opus_decoder_ctl(decoder, key, value as *mut i32) },
+            RawDecoder::Multistream(decoder) => unsafe { opus_sys::opus_multistream_decoder_ctl(decoder, key, value as *mut i32) },
+            RawDecoder::Projection(decoder) => unsafe { opus_sys::opus_projection_decoder_ctl(decoder, key, value as *mut i32) },
+        }
+    }
+
+    fn reset(&self) {
+        match *self {
+            RawDecoder::Single(decoder) => unsafe { opus_sys::opus_decoder_ctl(decoder, opus_sys::OPUS_RESET_STATE) },
+            RawDecoder::Multistream(decoder) => unsafe { opus_sys::opus_multistream_decoder_ctl(decoder, opus_sys::OPUS_RESET_STATE) },
+            RawDecoder::Projection(decoder) => unsafe { opus_sys::opus_projection_decoder_ctl(decoder, opus_sys::OPUS_RESET_STATE) },
+        };
+    }
+
+    fn decode(&self, data: &[u8], pcm: &mut [i16], frame_size: c_int, fec: bool) -> c_int {
+        let (data_ptr, data_len) = packet_ptr(data);
+
+        match *self {
+            RawDecoder::Single(decoder) => unsafe { opus_sys::opus_decode(decoder, data_ptr, data_len, pcm.as_mut_ptr(), frame_size, fec as c_int) },
+            RawDecoder::Multistream(decoder) => unsafe {
+                opus_sys::opus_multistream_decode(decoder, data_ptr, data_len, pcm.as_mut_ptr(), frame_size, fec as c_int)
+            },
+            RawDecoder::Projection(decoder) => unsafe {
+                opus_sys::opus_projection_decode(decoder, data_ptr, data_len, pcm.as_mut_ptr(), frame_size, fec as c_int)
+            },
+        }
+    }
+
+    fn decode_float(&self, data: &[u8], pcm: &mut [f32], frame_size: c_int, fec: bool) -> c_int {
+        let (data_ptr, data_len) = packet_ptr(data);
+
+        match *self {
+            RawDecoder::Single(decoder) => unsafe {
+                opus_sys::opus_decode_float(decoder, data_ptr, data_len, pcm.as_mut_ptr(), frame_size, fec as c_int)
+            },
+            RawDecoder::Multistream(decoder) => unsafe {
+                opus_sys::opus_multistream_decode_float(decoder, data_ptr, data_len, pcm.as_mut_ptr(), frame_size, fec as c_int)
+            },
+            RawDecoder::Projection(decoder) => unsafe {
+                opus_sys::opus_projection_decode_float(decoder, data_ptr, data_len, pcm.as_mut_ptr(), frame_size, fec as c_int)
+            },
+        }
+    }
+}
+
+impl RawDecoder {
+    fn dred_decode(&self, dred: &DredState, offset: u32, pcm: &mut [i16], frame_size: c_int) -> c_int {
+        match *self {
+            RawDecoder::Single(decoder) => unsafe {
+                opus_sys::opus_decoder_dred_decode(decoder, dred.dred, offset as opus_sys::opus_int32, pcm.as_mut_ptr(), frame_size)
+            },
+            _ => opus_sys::OPUS_UNIMPLEMENTED,
+        }
+    }
+
+    fn dred_decode_float(&self, dred: &DredState, offset: u32, pcm: &mut [f32], frame_size: c_int) -> c_int {
+        match *self {
+            RawDecoder::Single(decoder) => unsafe {
+                opus_sys::opus_decoder_dred_decode_float(decoder, dred.dred, offset as opus_sys::opus_int32, pcm.as_mut_ptr(), frame_size)
+            },
+            _ => opus_sys::OPUS_UNIMPLEMENTED,
+        }
+    }
+}
+
+impl Drop for RawDecoder {
+    fn drop(&mut self) {
+        match *self {
+            RawDecoder::Single(decoder) => unsafe { opus_sys::opus_decoder_destroy(decoder) },
+            RawDecoder::Multistream(decoder) => unsafe { opus_sys::opus_multistream_decoder_destroy(decoder) },
+            RawDecoder::Projection(decoder) => unsafe { opus_sys::opus_projection_decoder_destroy(decoder) },
+        }
+    }
+}
+
+pub struct OpusDecoder {
+    decoder: RawDecoder,
     pending: VecDeque<SharedFrame<AudioFrame<'static>>>,
     packet_loss: bool,
     fec: bool,
+    // Whether timestamp gaps are DTX silences rather than lost packets, except for
+    // the packets reported lost by lost_packets or packet_loss
+    dtx: bool,
+    // Number of packets lost right before the next one, as reported by the
+    // transport, such as the RTP sequence number gap
+    lost_packets: u32,
+    // Expected position of the next packet, in samples
+    next_position: Option<i64>,
+    time_base: Option<Rational64>,
+    // Samples left to discard at the start of the stream
+    pre_skip: u32,
+    sample_rate: u32,
+    channels: u8,
+    dred: Option<DredState>,
 }
 
 unsafe impl Send for OpusDecoder {}
 unsafe impl Sync for OpusDecoder {}
 
 impl Codec<AudioDecoder> for OpusDecoder {
-    fn configure(&mut self, _params: Option<&CodecParameters>, _options: Option<&Variant>) -> Result<()> {
+    fn configure(&mut self, _params: Option<&CodecParameters>, options: Option<&Variant>) -> Result<()> {
+        if let Some(options) = options {
+            self.fec = options["fec"].get_bool().unwrap_or(false);
+            self.dtx = options["dtx"].get_bool().unwrap_or(false);
+        }
+
         Ok(())
     }
 
@@ -51,6 +244,20 @@ impl Codec<AudioDecoder> for OpusDecoder {
                 self.fec = value != 0;
                 Ok(())
             }
+            "dtx" => {
+                self.dtx = value != 0;
+                Ok(())
+            }
+            "lost_packets" => {
+                self.lost_packets = value.max(0) as u32;
+                Ok(())
+            }
+            // Samples at 48kHz to discard from the next decoded output, such as the
+            // pre-roll after seeking
+            "pre_skip" => {
+                self.pre_skip = (value.max(0) as u64 * self.sample_rate as u64 / 48000) as u32;
+                Ok(())
+            }
             _ => Err(unsupported_error!(key)),
         }
     }
@@ -59,19 +266,65 @@ impl Codec<AudioDecoder> for OpusDecoder {
 impl Decoder<AudioDecoder> for OpusDecoder {
     fn send_packet(&mut self, config: &AudioDecoder, pool: Option<&Arc<FramePool<AudioFrame<'static>>>>, packet: &Packet) -> Result<()> {
         let desc = self.create_descriptor(config)?;
-        let fec = self.fec && self.packet_loss;
+        let packet_data = packet.data();
+
+        // An empty packet signals a lost packet, conceal it using the duration of the
+        // last packet
+        if packet_data.is_empty() {
+            let frame_size = self.concealment_frame_size(&desc)?;
+            return self.conceal(pool, &desc, frame_size);
+        }
 
-        if fec {
-            let mut frame = self.get_frame(pool, &desc)?;
-            self.decode(&desc, packet, frame.write().unwrap(), true)?;
-            self.pending.push_back(frame);
+        if packet.time_base.is_some() {
+            self.time_base = packet.time_base;
+        }
+
+        if let Some(position) = Self::packet_position(packet, &desc) {
+            let lost_samples = self.next_position.map_or(0, |next_position| position - next_position);
+
+            // Ignore gaps shorter than the smallest Opus frame, they are rounding errors,
+            // and treat gaps longer than what can be recovered as discontinuities, such
+            // as a stream restart, whose timestamps are followed without concealment
+            let granularity = desc.sample_rate.get() / 400;
+            if lost_samples >= granularity as i64 && lost_samples <= self.max_lost_samples(&desc) {
+                // Nothing was sent during DTX, so only the packets reported lost right
+                // before this one are recovered, and the rest of the gap is filled with
+                // comfort noise
+                if self.dtx && !self.packet_loss {
+                    let packet_samples = Self::packet_frame_size(packet_data, &desc) * Self::packet_frame_count(packet_data);
+                    let recovered_samples = (self.lost_packets as i64 * packet_samples as i64).min(lost_samples);
+                    let silent_samples = (lost_samples - recovered_samples) as u32 / granularity * granularity;
+
+                    if silent_samples > 0 {
+                        self.conceal(pool, &desc, silent_samples)?;
+                    }
+
+                    if recovered_samples > 0 {
+                        self.recover(pool, &desc, packet_data, recovered_samples as u32)?;
+                    }
+                } else {
+                    self.recover(pool, &desc, packet_data, lost_samples as u32)?;
+                }
+                self.packet_loss = false;
+            }
+
+            self.next_position = Some(position);
+        }
+
+        self.lost_packets = 0;
+
+        // Without timestamps, the lost packet is assumed to last as long as this one
+        if self.packet_loss && (self.fec || self.dred.is_some()) {
+            let frame_size = Self::packet_frame_size(packet_data, &desc) * Self::packet_frame_count(packet_data);
+            self.recover(pool, &desc, packet_data, frame_size)?;
             self.packet_loss = false;
         }
 
-        if !packet.data().is_empty() {
-            let mut frame = self.get_frame(pool, &desc)?;
-            self.decode(&desc, packet, frame.write().unwrap(), false)?;
-            self.pending.push_back(frame);
+        self.decode_to_pending(pool, &desc, Source::Packet(packet_data), desc.samples.get())?;
+
+        // A packet shorter than its decoded audio marks the trimmed end of the stream
+        if let Some(end_position) = Self::packet_end_position(packet, &desc) {
+            self.trim_end(end_position)?;
         }
 
         Ok(())
@@ -86,45 +339,206 @@ impl Decoder<AudioDecoder> for OpusDecoder {
     }
 
     fn flush(&mut self, _config: &AudioDecoder) -> Result<()> {
-        unsafe { opus_sys::opus_decoder_ctl(self.decoder, opus_sys::OPUS_RESET_STATE) };
+        self.decoder.reset();
+        self.packet_loss = false;
+        self.lost_packets = 0;
+        self.next_position = None;
+        self.time_base = None;
+        self.pre_skip = 0;
         Ok(())
     }
 }
 
-impl Drop for OpusDecoder {
-    fn drop(&mut self) {
-        unsafe { opus_sys::opus_decoder_destroy(self.decoder) }
-    }
-}
-
 const DEFAULT_PACKET_PENDING_CAPACITY: usize = 2;
 
+// Longest gaps recovered with PLC and FEC, and with Deep REDundancy, in
+// milliseconds
+const MAX_CONCEALMENT_DURATION: u32 = 120;
+const MAX_DRED_DURATION: u32 = 1000;
+
+// Stream count, coupled stream count and mapping table of the mapping family
+// 1 for each channel count, in the Vorbis channel order
+const VORBIS_MAPPINGS: [(u8, u8, &[u8]); 8] = [
+    (1, 0, &[0]),
+    (1, 1, &[0, 1]),
+    (2, 1, &[0, 2, 1]),
+    (2, 2, &[0, 1, 2, 3]),
+    (3, 2, &[0, 4, 1, 2, 3]),
+    (4, 2, &[0, 4, 1, 2, 3, 5]),
+    (4, 3, &[0, 4, 1, 2, 3, 5, 6]),
+    (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]),
+];
+
 impl OpusDecoder {
-    pub fn new(codec_id: CodecID, params: &AudioDecoderParameters, _options: Option<&Variant>) -> Result<Self> {
+    pub fn new(codec_id: CodecID, params: &AudioDecoderParameters, options: Option<&Variant>) -> Result<Self> {
         if codec_id != CodecID::OPUS {
             return Err(unsupported_error!(codec_id));
         }
 
         let audio_params = &params.audio;
         let sample_rate = audio_params.sample_rate.ok_or_else(|| invalid_param_error!(params))?.get() as opus_sys::opus_int32;
-        let channels = audio_params.channel_layout.as_ref().ok_or_else(|| invalid_param_error!(params))?.channels.get() as c_int;
+        let channel_layout = audio_params.channel_layout.as_ref().ok_or_else(|| invalid_param_error!(params))?;
+        let channels = channel_layout.channels.get() as c_int;
+
+        let head = match params.decoder.extra_data.as_deref() {
+            Some(extra_data) if !extra_data.is_empty() => Some(OpusHead::parse(extra_data)?),
+            _ => None,
+        };
+
+        // Without an identification header, surround layouts use the mapping family 1
+        let mapping_family = match &head {
+            Some(head) => head.mapping_family,
+            None if channels > 2 => 1,
+            None => 0,
+        };
 
         let mut ret = 0;
-        let decoder = unsafe { opus_sys::opus_decoder_create(sample_rate, channels, &mut ret) };
-        if decoder.is_null() || ret != opus_sys::OPUS_OK {
-            return Err(Error::CreationFailed(opus_error_string(ret)));
-        }
+        let decoder = match mapping_family {
+            0 => {
+                // The identification header is authoritative for the channel count
+                if head.as_ref().is_some_and(|head| head.channels as c_int != channels) {
+                    return Err(Error::Invalid("channel count".into()));
+                }
+
+                let decoder = unsafe { opus_sys::opus_decoder_create(sample_rate, channels, &mut ret) };
+                if decoder.is_null() || ret != opus_sys::OPUS_OK {
+                    return Err(Error::CreationFailed(opus_error_string(ret)));
+                }
+
+                RawDecoder::Single(decoder)
+            }
+            // Ambisonics, demixed with the matrix carried by the identification header
+            3 => {
+                let head = head.as_ref().ok_or_else(|| invalid_param_error!(params))?;
+                if head.channels as c_int != channels {
+                    return Err(Error::Invalid("channel count".into()));
+                }
 
-        Ok(OpusDecoder {
+                let mut demixing_matrix = head.demixing_matrix.clone();
+                let decoder = unsafe {
+                    opus_sys::opus_projection_decoder_create(
+                        sample_rate,
+                        channels,
+                        head.stream_count as c_int,
+                        head.coupled_count as c_int,
+                        demixing_matrix.as_mut_ptr(),
+                        demixing_matrix.len() as opus_sys::opus_int32,
+                        &mut ret,
+                    )
+                };
+                if decoder.is_null() || ret != opus_sys::OPUS_OK {
+                    return Err(Error::CreationFailed(opus_error_string(ret)));
+                }
+
+                RawDecoder::Projection(decoder)
+            }
+            _ => {
+                let (streams, coupled_streams, mapping) = Self::stream_mapping(head.as_ref(), mapping_family, channel_layout)?;
+                let decoder = unsafe {
+                    opus_sys::opus_multistream_decoder_create(
+                        sample_rate,
+                        channels,
+                        streams as c_int,
+                        coupled_streams as c_int,
+                        mapping.as_ptr(),
+                        &mut ret,
+                    )
+                };
+                if decoder.is_null() || ret != opus_sys::OPUS_OK {
+                    return Err(Error::CreationFailed(opus_error_string(ret)));
+                }
+
+                RawDecoder::Multistream(decoder)
+            }
+        };
+
+        // Deep REDundancy is only available with a single stream, and libopus may
+        // be built without it
+        let dred = match decoder {
+            RawDecoder::Single(_) => DredState::new().ok(),
+            _ => None,
+        };
+
+        let mut decoder = OpusDecoder {
             decoder,
             pending: VecDeque::with_capacity(DEFAULT_PACKET_PENDING_CAPACITY),
             packet_loss: false,
             fec: false,
-        })
+            dtx: false,
+            lost_packets: 0,
+            next_position: None,
+            time_base: None,
+            pre_skip: 0,
+            sample_rate: sample_rate as u32,
+            channels: channels as u8,
+            dred,
+        };
+
+        decoder.configure(None, options)?;
+
+        if let Some(head) = head {
+            decoder.decoder_ctl(opus_sys::OPUS_SET_GAIN_REQUEST, head.output_gain as i32)?;
+            // Pre-skip is expressed at 48kHz
+            decoder.pre_skip = (head.pre_skip as u64 * sample_rate as u64 / 48000) as u32;
+        }
+
+        Ok(decoder)
+    }
+
+    /// SDP format parameters describing the stream this decoder prefers to
+    /// receive, to be combined with the sender properties of the local encoder
+    pub fn fmtp(&self) -> OpusFmtp {
+        OpusFmtp {
+            max_playback_rate: Some(self.sample_rate),
+            stereo: Some(self.channels == 2),
+            use_inband_fec: Some(self.fec),
+            ..OpusFmtp::default()
+        }
+    }
+
+    /// Pitch period, in samples, of the last decoded frame, or 0 if it was not
+    /// voiced. Only available with a single stream
+    pub fn pitch(&self) -> Result<u32> {
+        Ok(self.decoder_ctl_get(opus_sys::OPUS_GET_PITCH_REQUEST)?.max(0) as u32)
+    }
+
+    // Returns the stream layout with the mapping table reordered so that the
+    // decoder outputs channels in the channel layout order
+    fn stream_mapping(head: Option<&OpusHead>, mapping_family: u8, channel_layout: &ChannelLayout) -> Result<(u8, u8, Vec<u8>)> {
+        let channels = channel_layout.channels.get() as c_int;
+        let (streams, coupled_streams, mapping) = match head {
+            Some(head) => {
+                if head.channels as c_int != channels {
+                    return Err(Error::Invalid("channel count".into()));
+                }
+
+                (head.stream_count, head.coupled_count, head.mapping.clone())
+            }
+            None => {
+                let (streams, coupled_streams, mapping) = *VORBIS_MAPPINGS.get(channels as usize - 1).ok_or_else(|| unsupported_error!(channels))?;
+                (streams, coupled_streams, mapping.to_vec())
+            }
+        };
+
+        match mapping_family {
+            1 => {
+                let channel_order = vorbis_channel_order(channel_layout)?;
+                let mut reordered = vec![0u8; mapping.len()];
+
+                for (index, &channel) in channel_order.iter().enumerate() {
+                    reordered[channel] = mapping[index];
+                }
+
+                Ok((streams, coupled_streams, reordered))
+            }
+            // Channels are used in the order of the mapping table
+            255 => Ok((streams, coupled_streams, mapping)),
+            _ => Err(unsupported_error!(mapping_family)),
+        }
     }
 
     fn decoder_ctl(&mut self, key: i32, value: i32) -> Result<()> {
-        let ret = unsafe { opus_sys::opus_decoder_ctl(self.decoder, key, value) };
+        let ret = self.decoder.ctl(key, value);
 
         if ret != opus_sys::OPUS_OK {
             return Err(Error::SetFailed(opus_error_string(ret)));
@@ -133,6 +547,233 @@ impl OpusDecoder {
         Ok(())
     }
 
+    fn decoder_ctl_get(&self, key: i32) -> Result<i32> {
+        let mut value = 0;
+        let ret = self.decoder.ctl_get(key, &mut value);
+
+        if ret != opus_sys::OPUS_OK {
+            return Err(Error::Failed(opus_error_string(ret)));
+        }
+
+        Ok(value)
+    }
+
+    fn concealment_frame_size(&mut self, desc: &AudioFrameDescriptor) -> Result<u32> {
+        let last_duration = self.decoder_ctl_get(opus_sys::OPUS_GET_LAST_PACKET_DURATION_REQUEST)?;
+
+        // Nothing has been decoded yet, fall back to 20ms
+        if last_duration <= 0 {
+            return Ok(desc.sample_rate.get() / 50);
+        }
+
+        Ok((last_duration as u32).min(desc.samples.get()))
+    }
+
+    fn max_lost_samples(&self, desc: &AudioFrameDescriptor) -> i64 {
+        let duration = if self.dred.is_some() {
+            MAX_DRED_DURATION
+        } else {
+            MAX_CONCEALMENT_DURATION
+        };
+
+        (desc.sample_rate.get() as u64 * duration as u64 / 1000) as i64
+    }
+
+    fn packet_position(packet: &Packet, desc: &AudioFrameDescriptor) -> Option<i64> {
+        let pts = packet.pts?;
+        let time_base = packet.time_base?;
+
+        Some((time_base * pts * desc.sample_rate.get() as i64).to_integer())
+    }
+
+    fn packet_end_position(packet: &Packet, desc: &AudioFrameDescriptor) -> Option<i64> {
+        let position = Self::packet_position(packet, desc)?;
+        let duration = packet.duration?;
+        let time_base = packet.time_base?;
+
+        Some(position + (time_base * duration * desc.sample_rate.get() as i64).to_integer())
+    }
+
+    fn trim_end(&mut self, end_position: i64) -> Result<()> {
+        let Some(next_position) = self.next_position else {
+            return Ok(());
+        };
+
+        let Some(frame) = self.pending.back_mut() else {
+            return Ok(());
+        };
+
+        let audio_frame = frame.write().unwrap();
+        let samples = audio_frame.descriptor().samples.get() as i64;
+        let excess = next_position - end_position;
+        // Durations in a time base coarser than the sample rate are rounded, so a
+        // shortfall within one tick is not a trimmed end
+        let tick = self.time_base.map_or(1, |time_base| (time_base * self.sample_rate as i64).ceil().to_integer());
+
+        if excess < tick.max(1) || excess > samples {
+            return Ok(());
+        }
+
+        if excess == samples {
+            self.pending.pop_back();
+            return Ok(());
+        }
+
+        audio_frame.truncate((samples - excess) as u32)?;
+
+        if let (Some(pts), Some(time_base)) = (audio_frame.pts, audio_frame.time_base) {
+            let desc = audio_frame.descriptor().clone();
+            audio_frame.duration = Some(Self::samples_to_time(end_position, time_base, &desc) - pts);
+        }
+
+        Ok(())
+    }
+
+    fn samples_to_time(samples: i64, time_base: Rational64, desc: &AudioFrameDescriptor) -> i64 {
+        (Rational64::new(samples, desc.sample_rate.get() as i64) / time_base).to_integer()
+    }
+
+    fn packet_frame_size(data: &[u8], desc: &AudioFrameDescriptor) -> u32 {
+        unsafe { opus_sys::opus_packet_get_samples_per_frame(data.as_ptr(), desc.sample_rate.get() as opus_sys::opus_int32) as u32 }
+    }
+
+    fn packet_frame_count(data: &[u8]) -> u32 {
+        unsafe { opus_sys::opus_packet_get_nb_frames(data.as_ptr(), data.len() as opus_sys::opus_int32).max(1) as u32 }
+    }
+
+    fn recover(
+        &mut self,
+        pool: Option<&Arc<FramePool<AudioFrame<'static>>>>,
+        desc: &AudioFrameDescriptor,
+        data: &[u8],
+        lost_samples: u32,
+    ) -> Result<()> {
+        // Concealment works in multiples of 2.5ms
+        let granularity = desc.sample_rate.get() / 400;
+        let lost_samples = lost_samples / granularity * granularity;
+
+        // Deep REDundancy carries up to 1s of audio preceding the packet, except for
+        // the most recent samples
+        let (dred_start, dred_end) = match self.dred.as_mut() {
+            Some(dred) => dred.parse(data, lost_samples.min(desc.sample_rate.get()), desc.sample_rate.get()),
+            None => (0, 0),
+        };
+        let dred_start = dred_start / granularity * granularity;
+        let dred_end = dred_end.div_ceil(granularity) * granularity;
+        let has_dred = |offset: u32| offset <= dred_start && offset > dred_end;
+
+        // The LBRR data of the next packet can only restore the frame right before it
+        let has_lbrr = unsafe { opus_sys::opus_packet_has_lbrr(data.as_ptr(), data.len() as opus_sys::opus_int32) } > 0;
+        let lbrr_samples = if self.fec && has_lbrr {
+            Self::packet_frame_size(data, desc)
+        } else {
+            0
+        };
+        let lbrr_samples = if lbrr_samples <= lost_samples {
+            lbrr_samples
+        } else {
+            0
+        };
+
+        let max_frame_size = self.concealment_frame_size(desc)?;
+        let mut remaining = lost_samples;
+
+        // Use the redundancy where it covers the gap, and PLC for whatever it cannot
+        // cover
+        while remaining > 0 {
+            let (source, frame_size) = if has_dred(remaining) {
+                (Source::Dred(remaining), remaining.min(max_frame_size).min(remaining - dred_end))
+            } else if remaining == lbrr_samples {
+                (Source::Fec(data), lbrr_samples)
+            } else {
+                // Conceal up to where the redundancy takes over
+                let next_dred = if has_dred(dred_start) && dred_start < remaining {
+                    dred_start
+                } else {
+                    0
+                };
+                let next_lbrr = if lbrr_samples < remaining {
+                    lbrr_samples
+                } else {
+                    0
+                };
+                let next = next_dred.max(next_lbrr);
+                (Source::Conceal, remaining.min(max_frame_size).min(remaining - next))
+            };
+
+            self.decode_to_pending(pool, desc, source, frame_size)?;
+            remaining -= frame_size;
+        }
+
+        Ok(())
+    }
+
+    fn conceal(&mut self, pool: Option<&Arc<FramePool<AudioFrame<'static>>>>, desc: &AudioFrameDescriptor, samples: u32) -> Result<()> {
+        let mut remaining = samples;
+
+        while remaining > 0 {
+            let frame_size = remaining.min(desc.samples.get());
+            self.decode_to_pending(pool, desc, Source::Conceal, frame_size)?;
+            remaining -= frame_size;
+        }
+
+        Ok(())
+    }
+
+    fn decode_to_pending(
+        &mut self,
+        pool: Option<&Arc<FramePool<AudioFrame<'static>>>>,
+        desc: &AudioFrameDescriptor,
+        source: Source,
+        frame_size: u32,
+    ) -> Result<()> {
+        let mut frame = self.get_frame(pool, desc)?;
+        let audio_frame = frame.write().unwrap();
+        let decoded = self.decode(desc, source, frame_size, audio_frame)?;
+        let skipped = decoded.min(self.pre_skip);
+        let samples = decoded - skipped;
+
+        self.pre_skip -= skipped;
+
+        // Streams without timestamps start at zero
+        let position = self.next_position.unwrap_or(0) + skipped as i64;
+        if samples == 0 {
+            self.next_position = Some(position);
+            return Ok(());
+        }
+
+        if skipped > 0 {
+            Self::discard(desc, audio_frame, skipped, samples)?;
+        }
+
+        let next_position = position + samples as i64;
+        let time_base = self.time_base.unwrap_or_else(|| Rational64::new(1, desc.sample_rate.get() as i64));
+        let pts = Self::samples_to_time(position, time_base, desc);
+
+        audio_frame.pts = Some(pts);
+        audio_frame.duration = Some(Self::samples_to_time(next_position, time_base, desc) - pts);
+        audio_frame.time_base = Some(time_base);
+
+        self.next_position = Some(next_position);
+        self.pending.push_back(frame);
+
+        Ok(())
+    }
+
+    fn discard(desc: &AudioFrameDescriptor, frame: &mut AudioFrame, skipped: u32, samples: u32) -> Result<()> {
+        let sample_size = desc.channels().get() as usize * desc.format.bytes() as usize;
+
+        if let Ok(mut guard) = frame.map_mut() {
+            let mut planes = guard.planes_mut().unwrap();
+            let data = planes.plane_data_mut(0).unwrap();
+            data.copy_within(skipped as usize * sample_size..(skipped + samples) as usize * sample_size, 0);
+        } else {
+            return Err(Error::Invalid("not writable".into()));
+        }
+
+        frame.truncate(samples)
+    }
+
     fn get_frame(&self, pool: Option<&Arc<FramePool<AudioFrame<'static>>>>, desc: &AudioFrameDescriptor) -> Result<SharedFrame<AudioFrame<'static>>> {
         if let Some(pool) = pool {
             pool.get_frame_with_descriptor(desc.clone())
@@ -156,39 +797,27 @@ impl OpusDecoder {
         AudioFrameDescriptor::try_from_channel_layout(sample_format, max_samples, sample_rate, channel_layout.clone())
     }
 
-    fn decode(&mut self, desc: &AudioFrameDescriptor, packet: &Packet, frame: &mut AudioFrame, fec: bool) -> Result<()> {
+    fn decode(&mut self, desc: &AudioFrameDescriptor, source: Source, frame_size: u32, frame: &mut AudioFrame) -> Result<u32> {
+        let (data, fec) = match source {
+            Source::Packet(data) => (data, false),
+            Source::Fec(data) => (data, true),
+            _ => (&[][..], false),
+        };
+
         let ret = if let Ok(mut guard) = frame.map_mut() {
             let mut planes = guard.planes_mut().unwrap();
-            let packet_data = packet.data();
-            let frame_size = if fec {
-                unsafe { opus_sys::opus_packet_get_samples_per_frame(packet_data.as_ptr(), desc.sample_rate.get() as opus_sys::opus_int32) }
-            } else {
-                desc.samples.get() as c_int
-            };
 
             if desc.format == SampleFormat::F32 {
-                let data = bytemuck::cast_slice_mut::<u8, f32>(planes.plane_data_mut(0).unwrap());
-                unsafe {
-                    opus_sys::opus_decode_float(
-                        self.decoder,
-                        packet_data.as_ptr(),
-                        packet_data.len() as opus_sys::opus_int32,
-                        data.as_mut_ptr(),
-                        frame_size,
-                        fec as c_int,
-                    )
+                let pcm = bytemuck::cast_slice_mut::<u8, f32>(planes.plane_data_mut(0).unwrap());
+                match (source, &self.dred) {
+                    (Source::Dred(offset), Some(dred)) => self.decoder.dred_decode_float(dred, offset, pcm, frame_size as c_int),
+                    _ => self.decoder.decode_float(data, pcm, frame_size as c_int, fec),
                 }
             } else {
-                let data = bytemuck::cast_slice_mut::<u8, i16>(planes.plane_data_mut(0).unwrap());
-                unsafe {
-                    opus_sys::opus_decode(
-                        self.decoder,
-                        packet_data.as_ptr(),
-                        packet_data.len() as opus_sys::opus_int32,
-                        data.as_mut_ptr(),
-                        frame_size,
-                        fec as c_int,
-                    )
+                let pcm = bytemuck::cast_slice_mut::<u8, i16>(planes.plane_data_mut(0).unwrap());
+                match (source, &self.dred) {
+                    (Source::Dred(offset), Some(dred)) => self.decoder.dred_decode(dred, offset, pcm, frame_size as c_int),
+                    _ => self.decoder.decode(data, pcm, frame_size as c_int, fec),
                 }
             }
         } else {
@@ -203,7 +832,7 @@ impl OpusDecoder {
 
         frame.truncate(samples)?;
 
-        Ok(())
+        Ok(samples)
     }
 }
 
@@ -241,3 +870,520 @@ impl CodecInformation for OpusDecoder {
 pub fn initialize() {
     register_decoder(Arc::new(OpusDecoderBuilder), false);
 }
+
+// The decoder is fed with encoded audio
+#[cfg(all(test, feature = "encoder"))]
+mod tests {
+    use std::num::{NonZeroU32, NonZeroU8};
+
+    use media_codec::{
+        decoder::DecoderParameters,
+        encoder::{AudioEncoder, AudioEncoderParameters, Encoder, EncoderParameters},
+        AudioParameters,
+    };
+    use media_core::audio::{Channel, ChannelOrder};
+
+    use super::*;
+    use crate::encoder::OpusEncoder;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const FRAME_SIZE: u32 = 960;
+
+    fn audio_params() -> AudioParameters {
+        layout_params(ChannelLayout::MONO)
+    }
+
+    fn layout_params(channel_layout: ChannelLayout) -> AudioParameters {
+        AudioParameters {
+            format: Some(SampleFormat::S16),
+            samples: None,
+            sample_rate: NonZeroU32::new(SAMPLE_RATE),
+            channel_layout: Some(channel_layout),
+        }
+    }
+
+    // Encodes 20ms mono frames of a voiced signal with a 150Hz pitch, with the
+    // packet pts in samples
+    fn encode(options: &[(&str, Variant)], frames: u32) -> (Vec<Packet<'static>>, Vec<u8>) {
+        let mut config = Variant::new_dict();
+        for (key, value) in options {
+            config.dict_set(key, value.clone());
+        }
+
+        let params = AudioEncoderParameters {
+            audio: audio_params(),
+            encoder: EncoderParameters::default(),
+        };
+        let encoder_config = AudioEncoder {
+            audio: audio_params(),
+            encoder: EncoderParameters::default(),
+            frame_size: None,
+            delay: None,
+        };
+        let mut encoder = OpusEncoder::new(CodecID::OPUS, &params, Some(&config)).unwrap();
+
+        let mut packets = Vec::new();
+        for index in 0..frames {
+            let data: Vec<u8> = (0..FRAME_SIZE)
+                .flat_map(|sample| {
+                    let time = (index * FRAME_SIZE + sample) as f32 / SAMPLE_RATE as f32;
+                    let voice: f32 = (1..10).map(|harmonic| (time * 150.0 * harmonic as f32 * std::f32::consts::TAU).sin() / harmonic as f32).sum();
+                    ((voice * 5000.0) as i16).to_ne_bytes()
+                })
+                .collect();
+            let frame = AudioFrame::from_buffer(SampleFormat::S16, 1, FRAME_SIZE, SAMPLE_RATE, data).unwrap();
+            encoder.send_frame(&encoder_config, None, SharedFrame::<AudioFrame<'static>>::new(frame)).unwrap();
+
+            while let Ok(packet) = encoder.receive_packet(&encoder_config, None) {
+                packets.push(packet);
+            }
+        }
+
+        (packets, encoder.extra_data().to_vec())
+    }
+
+    fn decoder(extra_data: Option<Vec<u8>>, options: &[(&str, Variant)]) -> (OpusDecoder, AudioDecoder) {
+        let mut config = Variant::new_dict();
+        for (key, value) in options {
+            config.dict_set(key, value.clone());
+        }
+
+        let params = AudioDecoderParameters {
+            audio: audio_params(),
+            decoder: DecoderParameters {
+                extra_data,
+                use_pool: None,
+            },
+        };
+        let decoder = OpusDecoder::new(CodecID::OPUS, &params, Some(&config)).unwrap();
+
+        (
+            decoder,
+            AudioDecoder {
+                audio: params.audio,
+                decoder: params.decoder,
+            },
+        )
+    }
+
+    // Returns the pts, the sample count and the samples of each decoded frame
+    fn decode(decoder: &mut OpusDecoder, config: &AudioDecoder, packet: &Packet) -> Vec<(i64, u32, Vec<i16>)> {
+        decoder.send_packet(config, None, packet).unwrap();
+
+        let mut frames = Vec::new();
+        while let Ok(frame) = decoder.receive_frame(config, None) {
+            let frame = frame.read();
+            let samples = frame.descriptor().samples.get();
+            let guard = frame.map().unwrap();
+            let planes = guard.planes().unwrap();
+            let data = planes.plane_data(0).unwrap()[..samples as usize * frame.descriptor().channels().get() as usize * 2]
+                .chunks_exact(2)
+                .map(|bytes| i16::from_ne_bytes([bytes[0], bytes[1]]))
+                .collect();
+
+            // Durations are rounded to the time base
+            let time_base = frame.time_base.unwrap();
+            let error = Rational64::from_integer(frame.duration.unwrap()) * time_base - Rational64::new(samples as i64, SAMPLE_RATE as i64);
+            assert!(error < time_base && -error < time_base);
+            frames.push((frame.pts.unwrap(), samples, data));
+        }
+
+        frames
+    }
+
+    // Encodes a tone on a single input channel and returns the energy of each
+    // decoded channel
+    fn channel_energies(
+        new_encoder: fn(CodecID, &AudioEncoderParameters, Option<&Variant>) -> Result<OpusEncoder>,
+        channel_layout: ChannelLayout,
+        channel: usize,
+    ) -> Vec<f64> {
+        let channels = channel_layout.channels.get() as usize;
+        let params = AudioEncoderParameters {
+            audio: layout_params(channel_layout.clone()),
+            encoder: EncoderParameters::default(),
+        };
+        let encoder_config = AudioEncoder {
+            audio: params.audio.clone(),
+            encoder: EncoderParameters::default(),
+            frame_size: None,
+            delay: None,
+        };
+        let mut encoder = new_encoder(CodecID::OPUS, &params, None).unwrap();
+
+        let decoder_params = AudioDecoderParameters {
+            audio: params.audio.clone(),
+            decoder: DecoderParameters {
+                extra_data: Some(encoder.extra_data().to_vec()),
+                use_pool: None,
+            },
+        };
+        let mut decoder = OpusDecoder::new(CodecID::OPUS, &decoder_params, None).unwrap();
+        let decoder_config = AudioDecoder {
+            audio: decoder_params.audio,
+            decoder: decoder_params.decoder,
+        };
+
+        let mut energies = vec![0.0; channels];
+        for index in 0..25 {
+            let mut data = vec![0i16; FRAME_SIZE as usize * channels];
+            for sample in 0..FRAME_SIZE {
+                let time = (index * FRAME_SIZE + sample) as f32 / SAMPLE_RATE as f32;
+                data[sample as usize * channels + channel] = ((time * 440.0 * std::f32::consts::TAU).sin() * 10000.0) as i16;
+            }
+
+            let frame =
+                AudioFrame::from_buffer(SampleFormat::S16, channels as u8, FRAME_SIZE, SAMPLE_RATE, bytemuck::cast_slice(&data).to_vec()).unwrap();
+            encoder.send_frame(&encoder_config, None, SharedFrame::<AudioFrame<'static>>::new(frame)).unwrap();
+
+            while let Ok(packet) = encoder.receive_packet(&encoder_config, None) {
+                for (_, _, data) in decode(&mut decoder, &decoder_config, &packet) {
+                    for (index, sample) in data.iter().enumerate() {
+                        energies[index % channels] += *sample as f64 * *sample as f64;
+                    }
+                }
+            }
+        }
+
+        energies
+    }
+
+    fn assert_energy_on(energies: &[f64], channel: usize) {
+        for (index, energy) in energies.iter().enumerate() {
+            if index != channel {
+                assert!(energies[channel] > energy * 100.0, "{:?}", energies);
+            }
+        }
+    }
+
+    // SILK carries the LBRR data used for in-band FEC
+    fn fec_options() -> [(&'static str, Variant); 5] {
+        [
+            ("application", "voip".into()),
+            ("bit_rate", 32000u32.into()),
+            ("max_bandwidth", "wideband".into()),
+            ("fec", true.into()),
+            ("packet_loss_percent", 20u32.into()),
+        ]
+    }
+
+    fn frame_sizes(frames: &[(i64, u32, Vec<i16>)]) -> Vec<u32> {
+        frames.iter().map(|(_, samples, _)| *samples).collect()
+    }
+
+    fn assert_continuous(frames: &[(i64, u32, Vec<i16>)], start: i64) {
+        let mut position = start;
+        for (pts, samples, _) in frames {
+            assert_eq!(*pts, position);
+            position += *samples as i64;
+        }
+    }
+
+    #[test]
+    fn lost_packets_concealed() {
+        let (packets, _) = encode(&[], 10);
+        let (mut decoder, config) = decoder(None, &[]);
+
+        let mut frames = Vec::new();
+        for (index, packet) in packets.iter().enumerate() {
+            if index == 3 || index == 6 || index == 7 {
+                continue;
+            }
+
+            let decoded = decode(&mut decoder, &config, packet);
+            match index {
+                // One concealed frame per lost packet, with the last packet duration
+                4 => assert_eq!(frame_sizes(&decoded), [960, 960]),
+                8 => assert_eq!(frame_sizes(&decoded), [960, 960, 960]),
+                _ => assert_eq!(frame_sizes(&decoded), [960]),
+            }
+            frames.extend(decoded);
+        }
+
+        assert_continuous(&frames, 0);
+        assert_eq!(frames.iter().map(|(_, samples, _)| samples).sum::<u32>(), 10 * FRAME_SIZE);
+    }
+
+    #[test]
+    fn gap_granularity() {
+        let (mut packets, _) = encode(&[], 6);
+        let (mut decoder, config) = decoder(None, &[]);
+
+        // Gaps shorter than 2.5ms are rounding errors, longer ones are concealed in
+        // steps of 2.5ms
+        packets[2].pts = Some(2 * 960 + 100);
+        packets[3].pts = Some(3 * 960 + 100);
+        packets[4].pts = Some(4 * 960 + 400);
+        packets[5].pts = Some(5 * 960 + 400);
+
+        for (index, packet) in packets.iter().enumerate() {
+            let decoded = decode(&mut decoder, &config, packet);
+            match index {
+                4 => {
+                    assert_eq!(frame_sizes(&decoded), [240, 960]);
+                    assert_eq!(decoded[0].0, 4 * 960 + 100);
+                    assert_eq!(decoded[1].0, 4 * 960 + 400);
+                }
+                _ => {
+                    assert_eq!(frame_sizes(&decoded), [960]);
+                    assert_eq!(decoded[0].0, packet.pts.unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gap_limit() {
+        let (mut packets, _) = encode(&[], 4);
+        let (mut decoder, config) = decoder(None, &[]);
+        let max_lost_samples = if decoder.dred.is_some() {
+            SAMPLE_RATE as i64
+        } else {
+            SAMPLE_RATE as i64 * 120 / 1000
+        };
+
+        // Gaps up to the limit are concealed, longer ones are discontinuities
+        packets[2].pts = Some(2 * 960 + max_lost_samples);
+        packets[3].pts = Some(3 * 960 + 2 * max_lost_samples + 120);
+
+        assert_eq!(frame_sizes(&decode(&mut decoder, &config, &packets[0])), [960]);
+        assert_eq!(frame_sizes(&decode(&mut decoder, &config, &packets[1])), [960]);
+
+        let frames = decode(&mut decoder, &config, &packets[2]);
+        assert_continuous(&frames, 2 * 960);
+        assert_eq!(frames.iter().map(|(_, samples, _)| samples).sum::<u32>(), max_lost_samples as u32 + 960);
+
+        let frames = decode(&mut decoder, &config, &packets[3]);
+        assert_eq!(frame_sizes(&frames), [960]);
+        assert_eq!(frames[0].0, packets[3].pts.unwrap());
+    }
+
+    #[test]
+    fn concealment_frame_size() {
+        let (packets, _) = encode(&[("frame_duration", 10.0f32.into())], 4);
+        let (mut decoder, config) = decoder(None, &[]);
+
+        // Nothing decoded yet, 20ms are concealed
+        assert_eq!(frame_sizes(&decode(&mut decoder, &config, &Packet::new(0))), [960]);
+
+        // Then the duration of the last packet
+        assert_eq!(frame_sizes(&decode(&mut decoder, &config, &packets[0])), [480]);
+        assert_eq!(frame_sizes(&decode(&mut decoder, &config, &Packet::new(0))), [480]);
+    }
+
+    #[test]
+    fn fec_recovery() {
+        let (packets, _) = encode(&fec_options(), 10);
+        assert!(unsafe { opus_sys::opus_packet_has_lbrr(packets[6].data().as_ptr(), packets[6].len() as opus_sys::opus_int32) } > 0);
+
+        let (mut fec_decoder, config) = decoder(None, &[("fec", true.into())]);
+        let (mut plc_decoder, _) = decoder(None, &[]);
+
+        for (index, packet) in packets.iter().enumerate() {
+            if index == 4 || index == 5 {
+                continue;
+            }
+
+            let fec_frames = decode(&mut fec_decoder, &config, packet);
+            let plc_frames = decode(&mut plc_decoder, &config, packet);
+
+            if index == 6 {
+                // The LBRR data only restores the packet right before, the one before is
+                // concealed
+                assert_eq!(frame_sizes(&fec_frames), [960, 960, 960]);
+                assert_continuous(&fec_frames, 4 * 960);
+                assert_eq!(fec_frames[0].2, plc_frames[0].2);
+                assert_ne!(fec_frames[1].2, plc_frames[1].2);
+            } else {
+                assert_eq!(frame_sizes(&fec_frames), [960]);
+            }
+        }
+    }
+
+    #[test]
+    fn packet_loss_without_timestamps() {
+        let (packets, _) = encode(&fec_options(), 4);
+        let (mut decoder, config) = decoder(None, &[("fec", true.into())]);
+
+        let mut frames = Vec::new();
+        for (index, packet) in packets.iter().enumerate() {
+            if index == 2 {
+                decoder.set_option("packet_loss", &true.into()).unwrap();
+                continue;
+            }
+
+            let mut packet = Packet::from_slice(packet.data());
+            packet.pts = None;
+            frames.extend(decode(&mut decoder, &config, &packet));
+        }
+
+        // Without timestamps, frames are stamped from zero
+        assert_eq!(frame_sizes(&frames), [960; 4]);
+        assert_continuous(&frames, 0);
+    }
+
+    #[test]
+    fn fixed_packet_size() {
+        let (packets, _) = encode(&[("packet_size", 200u32.into())], 4);
+        let (mut decoder, config) = decoder(None, &[]);
+
+        for packet in &packets {
+            assert_eq!(packet.len(), 200);
+            assert_eq!(frame_sizes(&decode(&mut decoder, &config, packet)), [960]);
+        }
+    }
+
+    #[test]
+    fn time_base() {
+        let (packets, _) = encode(&[], 4);
+        let (mut decoder, config) = decoder(None, &[]);
+
+        let mut frames = Vec::new();
+        for (index, packet) in packets.iter().enumerate().filter(|(index, _)| *index != 2) {
+            let mut packet = Packet::from_slice(packet.data());
+            packet.pts = Some(1000 + index as i64 * 20);
+            packet.time_base = Some(Rational64::new(1, 1000));
+            frames.extend(decode(&mut decoder, &config, &packet));
+        }
+
+        // Frames, including the concealed one, are stamped in the packet time base
+        let pts: Vec<i64> = frames.iter().map(|(pts, _, _)| *pts).collect();
+        assert_eq!(pts, [1000, 1020, 1040, 1060]);
+    }
+
+    #[test]
+    fn dtx_gap() {
+        let (mut packets, _) = encode(&[], 3);
+        packets[1].pts = Some(4 * 960);
+        packets[2].pts = Some(8 * 960);
+
+        let (mut decoder, config) = decoder(None, &[("dtx", true.into())]);
+        assert_eq!(frame_sizes(&decode(&mut decoder, &config, &packets[0])), [960]);
+
+        // The silence is filled in one frame
+        let frames = decode(&mut decoder, &config, &packets[1]);
+        assert_eq!(frame_sizes(&frames), [2880, 960]);
+        assert_continuous(&frames, 960);
+
+        // Except for the packets reported lost, which are recovered
+        decoder.set_option("lost_packets", &1.into()).unwrap();
+        let frames = decode(&mut decoder, &config, &packets[2]);
+        assert_eq!(frame_sizes(&frames), [1920, 960, 960]);
+        assert_continuous(&frames, 5 * 960);
+    }
+
+    #[test]
+    fn pre_skip() {
+        let (packets, extra_data) = encode(&[], 4);
+        let pre_skip = OpusHead::parse(&extra_data).unwrap().pre_skip as u32;
+        assert!(pre_skip > 0 && pre_skip < FRAME_SIZE);
+
+        let (mut decoder, config) = decoder(Some(extra_data), &[]);
+
+        let frames = decode(&mut decoder, &config, &packets[0]);
+        assert_eq!(frame_sizes(&frames), [960 - pre_skip]);
+        assert_eq!(frames[0].0, pre_skip as i64);
+        assert_continuous(&decode(&mut decoder, &config, &packets[1]), 960);
+
+        // Flushing drops the pending pre-skip
+        decoder.set_option("pre_skip", &(2 * 960).into()).unwrap();
+        decoder.flush(&config).unwrap();
+        assert_eq!(frame_sizes(&decode(&mut decoder, &config, &packets[2])), [960]);
+
+        // The pre-roll after seeking may span several packets
+        decoder.flush(&config).unwrap();
+        decoder.set_option("pre_skip", &1200.into()).unwrap();
+        assert!(decode(&mut decoder, &config, &packets[0]).is_empty());
+        let frames = decode(&mut decoder, &config, &packets[1]);
+        assert_eq!(frame_sizes(&frames), [720]);
+        assert_eq!(frames[0].0, 1200);
+    }
+
+    #[test]
+    fn coarse_time_base() {
+        let (packets, _) = encode(&[("application", "lowdelay".into()), ("frame_duration", 2.5f32.into())], 2);
+        let (mut decoder, config) = decoder(None, &[]);
+
+        // 2.5ms packets last 2 or 3 ticks of 1ms
+        let mut frames = Vec::new();
+        for packet in &packets {
+            let position = packet.pts.unwrap();
+            let pts = position / 48;
+            let mut packet = Packet::from_slice(packet.data());
+            packet.pts = Some(pts);
+            packet.duration = Some((position + 120) / 48 - pts);
+            packet.time_base = Some(Rational64::new(1, 1000));
+            frames.extend(decode(&mut decoder, &config, &packet));
+        }
+
+        assert_eq!(frame_sizes(&frames), vec![120; packets.len()]);
+
+        // A shortfall of a whole tick trims the end of the stream
+        let (packets, _) = encode(&[], 2);
+        decoder.flush(&config).unwrap();
+        for (index, packet) in packets.iter().enumerate() {
+            let mut packet = Packet::from_slice(packet.data());
+            packet.pts = Some(index as i64 * 20);
+            packet.duration = Some(if index == packets.len() - 1 {
+                15
+            } else {
+                20
+            });
+            packet.time_base = Some(Rational64::new(1, 1000));
+
+            let expected = if index == packets.len() - 1 {
+                720
+            } else {
+                960
+            };
+            assert_eq!(frame_sizes(&decode(&mut decoder, &config, &packet)), [expected]);
+        }
+    }
+
+    #[test]
+    fn surround_channel_placement() {
+        let channel_layout = ChannelLayout::SURROUND_5_1_BACK;
+        let back_left = channel_layout.get_index_from_channel(Channel::BackLeft).unwrap();
+
+        assert_energy_on(&channel_energies(OpusEncoder::new_multistream, channel_layout, back_left), back_left);
+    }
+
+    #[test]
+    fn ambisonics_channel_placement() {
+        // First order ambisonics in ACN order, the tone is on the Y component
+        let channel_layout = ChannelLayout {
+            order: ChannelOrder::Unspecified,
+            channels: NonZeroU8::new(4).unwrap(),
+            ..Default::default()
+        };
+
+        assert_energy_on(&channel_energies(OpusEncoder::new_projection, channel_layout, 1), 1);
+    }
+
+    #[test]
+    fn fmtp() {
+        let (decoder, _) = decoder(None, &[("fec", true.into())]);
+        assert_eq!(decoder.fmtp().to_string(), "maxplaybackrate=48000;stereo=0;useinbandfec=1");
+    }
+
+    #[test]
+    fn pitch() {
+        let (packets, _) = encode(&fec_options(), 10);
+        let (mut decoder, config) = decoder(None, &[]);
+
+        assert_eq!(decoder.pitch().unwrap(), 0);
+
+        // The input has a 150Hz pitch, that is a 320 samples period
+        for packet in &packets {
+            decode(&mut decoder, &config, packet);
+        }
+        assert!(decoder.pitch().unwrap().abs_diff(320) <= 2);
+
+        let params = AudioDecoderParameters {
+            audio: layout_params(ChannelLayout::SURROUND_5_1_BACK),
+            decoder: DecoderParameters::default(),
+        };
+        let decoder = OpusDecoder::new(CodecID::OPUS, &params, None).unwrap();
+        assert!(decoder.pitch().is_err());
+    }
+}
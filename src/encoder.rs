@@ -1,4 +1,4 @@
-use std::{collections::VecDeque, os::raw::c_int, sync::Arc};
+use std::{collections::VecDeque, ffi::CStr, fs, mem, os::raw::c_int, path::Path, str::FromStr, sync::Arc};
 
 use bytemuck;
 use ctor::ctor;
@@ -9,77 +9,518 @@ use media_codec::{
     CodecInformation, CodecParameters,
 };
 use media_core::{
-    audio::{AudioFrame, SampleFormat},
+    audio::{AudioFrame, ChannelOrder, SampleFormat},
     buffer::BufferPool,
     error::Error,
     frame::SharedFrame,
-    invalid_param_error,
+    invalid_param_error, not_found_error,
     rational::Rational64,
     unsupported_error,
     variant::Variant,
     Result,
 };
 
-use crate::{opus_error_string, opus_sys};
+use crate::{
+    header::{OpusHead, OpusTags},
+    opus_error_string, opus_sys,
+    packet::{multistream_pad, pad},
+    sdp::OpusFmtp,
+    vorbis_channel_order, Application, Bandwidth, Signal,
+};
 
-struct OpusOptions {
-    application: i32,
-    frame_duration: f32,
-    frame_size: u32,
-    packet_loss: i32,
-    fec: bool,
-    vbr: u32,
-    max_bandwidth: u32,
-    complexity: u32,
+/// Rate control mode of the encoder
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VbrMode {
+    Cbr,
+    #[default]
+    Vbr,
+    /// Variable bitrate bounded like CBR over the long term
+    ConstrainedVbr,
+}
+
+impl VbrMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VbrMode::Cbr => "cbr",
+            VbrMode::Vbr => "vbr",
+            VbrMode::ConstrainedVbr => "constrained_vbr",
+        }
+    }
+}
+
+impl FromStr for VbrMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cbr" => Ok(VbrMode::Cbr),
+            "vbr" => Ok(VbrMode::Vbr),
+            "constrained_vbr" => Ok(VbrMode::ConstrainedVbr),
+            _ => Err(Error::Invalid(format!("VBR mode {}", s).into())),
+        }
+    }
+}
+
+/// Duration of the frames in each packet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameDuration {
+    Ms2_5,
+    Ms5,
+    Ms10,
+    #[default]
+    Ms20,
+    Ms40,
+    Ms60,
+    Ms80,
+    Ms100,
+    Ms120,
+}
+
+impl FrameDuration {
+    pub fn millis(&self) -> f32 {
+        self.samples(400) as f32 * 2.5
+    }
+
+    /// Number of samples per frame at the given sample rate
+    pub fn samples(&self, sample_rate: u32) -> u32 {
+        // Durations in units of 2.5ms
+        let units = match self {
+            FrameDuration::Ms2_5 => 1,
+            FrameDuration::Ms5 => 2,
+            FrameDuration::Ms10 => 4,
+            FrameDuration::Ms20 => 8,
+            FrameDuration::Ms40 => 16,
+            FrameDuration::Ms60 => 24,
+            FrameDuration::Ms80 => 32,
+            FrameDuration::Ms100 => 40,
+            FrameDuration::Ms120 => 48,
+        };
+
+        sample_rate / 400 * units
+    }
+
+    fn expert_frame_duration(&self) -> i32 {
+        match self {
+            FrameDuration::Ms2_5 => opus_sys::OPUS_FRAMESIZE_2_5_MS,
+            FrameDuration::Ms5 => opus_sys::OPUS_FRAMESIZE_5_MS,
+            FrameDuration::Ms10 => opus_sys::OPUS_FRAMESIZE_10_MS,
+            FrameDuration::Ms20 => opus_sys::OPUS_FRAMESIZE_20_MS,
+            FrameDuration::Ms40 => opus_sys::OPUS_FRAMESIZE_40_MS,
+            FrameDuration::Ms60 => opus_sys::OPUS_FRAMESIZE_60_MS,
+            FrameDuration::Ms80 => opus_sys::OPUS_FRAMESIZE_80_MS,
+            FrameDuration::Ms100 => opus_sys::OPUS_FRAMESIZE_100_MS,
+            FrameDuration::Ms120 => opus_sys::OPUS_FRAMESIZE_120_MS,
+        }
+    }
+}
+
+impl TryFrom<f32> for FrameDuration {
+    type Error = Error;
+
+    fn try_from(millis: f32) -> Result<Self> {
+        match (millis * 10.0).round() as u32 {
+            25 => Ok(FrameDuration::Ms2_5),
+            50 => Ok(FrameDuration::Ms5),
+            100 => Ok(FrameDuration::Ms10),
+            200 => Ok(FrameDuration::Ms20),
+            400 => Ok(FrameDuration::Ms40),
+            600 => Ok(FrameDuration::Ms60),
+            800 => Ok(FrameDuration::Ms80),
+            1000 => Ok(FrameDuration::Ms100),
+            1200 => Ok(FrameDuration::Ms120),
+            _ => Err(Error::Invalid(format!("frame duration {}ms", millis).into())),
+        }
+    }
 }
 
-impl Default for OpusOptions {
+/// Encoder configuration, which maps to and from the `Variant` options of the
+/// codec registry
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpusEncoderConfig {
+    pub application: Application,
+    pub frame_duration: FrameDuration,
+    pub signal: Signal,
+    /// Bitrate in bits per second, which libopus clamps to the range supported
+    /// by the channel count, or `None` to let the encoder choose
+    pub bit_rate: Option<u32>,
+    pub vbr: VbrMode,
+    pub max_bandwidth: Option<Bandwidth>,
+    /// Bandwidth to encode regardless of the bitrate, or `None` to let the
+    /// encoder choose up to `max_bandwidth`
+    pub bandwidth: Option<Bandwidth>,
+    /// From 0 to 10
+    pub complexity: u8,
+    /// Expected packet loss, from 0 to 100
+    pub packet_loss_percent: u8,
+    pub fec: bool,
+    pub dtx: bool,
+    /// Whether the frames not transmitted during DTX are still output as 1 or
+    /// 2 byte packets rather than left as gaps in the timestamps
+    pub keep_dtx_packets: bool,
+    /// Deep REDundancy duration in 10ms units, up to 100, or 0 to disable it
+    pub dred_duration: u8,
+    /// Fixed size every packet is padded to, or `None` to disable padding
+    pub packet_size: Option<u32>,
+    /// Number of channels to encode, or `None` to follow the input
+    pub force_channels: Option<u8>,
+    /// Bit depth of the input signal, from 8 to 24, below which the encoder
+    /// does not spend bits
+    pub lsb_depth: u8,
+    /// Whether frames are encoded without inter-frame prediction, so that each
+    /// decodes on its own at the cost of quality
+    pub prediction_disabled: bool,
+    /// Whether the stereo phase inversion is disabled, which improves the
+    /// quality of the stereo signal downmixed to mono
+    pub phase_inversion_disabled: bool,
+}
+
+impl Default for OpusEncoderConfig {
     fn default() -> Self {
-        OpusOptions {
-            application: opus_sys::OPUS_APPLICATION_AUDIO,
-            frame_duration: 20.0,
-            frame_size: 960,
-            packet_loss: 0,
-            fec: false,
-            vbr: 1,
-            max_bandwidth: 0,
+        OpusEncoderConfig {
+            application: Application::Audio,
+            frame_duration: FrameDuration::default(),
+            signal: Signal::default(),
+            bit_rate: None,
+            vbr: VbrMode::default(),
+            max_bandwidth: None,
+            bandwidth: None,
             complexity: 10,
+            packet_loss_percent: 0,
+            fec: false,
+            dtx: false,
+            keep_dtx_packets: false,
+            dred_duration: 0,
+            packet_size: None,
+            force_channels: None,
+            lsb_depth: 24,
+            prediction_disabled: false,
+            phase_inversion_disabled: false,
         }
     }
 }
 
-impl OpusOptions {
-    fn from_variant(variant: Option<&Variant>) -> Self {
-        if let Some(variant) = variant {
-            let application = variant["application"].get_int32().unwrap_or(opus_sys::OPUS_APPLICATION_AUDIO);
-            let frame_duration = variant["frame_duration"].get_float().unwrap_or(20.0);
-            let packet_loss = variant["packet_loss"].get_int32().unwrap_or(0);
-            let fec = variant["fec"].get_bool().unwrap_or(false);
-            let vbr = variant["vbr"].get_uint32().unwrap_or(1);
-            let max_bandwidth = variant["max_bandwidth"].get_uint32().unwrap_or(0);
-            let complexity = variant["complexity"].get_uint32().unwrap_or(10);
-
-            OpusOptions {
-                application,
-                frame_duration,
-                frame_size: (frame_duration * 48000f32 / 1000f32) as u32,
-                packet_loss,
-                fec,
-                vbr,
-                max_bandwidth,
-                complexity,
+impl OpusEncoderConfig {
+    /// Parses the options on top of the default configuration
+    pub fn from_variant(variant: &Variant) -> Result<Self> {
+        let mut config = Self::default();
+        config.update_from_variant(variant)?;
+
+        Ok(config)
+    }
+
+    /// Applies the options present in the dictionary, rejecting values of the
+    /// wrong type or out of range. Unknown keys are ignored, as the options of
+    /// the codec registry also hold the generic ones
+    pub fn update_from_variant(&mut self, variant: &Variant) -> Result<()> {
+        let Variant::Dict(options) = variant else {
+            return Err(invalid_param_error!(variant));
+        };
+
+        let mut config = self.clone();
+        for (key, value) in options {
+            config.set_known(key, value)?;
+        }
+
+        config.validate()?;
+        *self = config;
+
+        Ok(())
+    }
+
+    /// Sets a single option, without validating the whole configuration
+    pub fn set(&mut self, key: &str, value: &Variant) -> Result<()> {
+        if !self.set_known(key, value)? {
+            return Err(unsupported_error!(key));
+        }
+
+        Ok(())
+    }
+
+    // Sets the option and returns whether the key is known
+    fn set_known(&mut self, key: &str, value: &Variant) -> Result<bool> {
+        let invalid = || Error::Invalid(format!("{} value {:?}", key, value).into());
+        let get_bool = || match value {
+            Variant::Bool(value) => Some(*value),
+            _ => value.get_int32().map(|value| value != 0),
+        };
+        let get_u32 = || value.get_uint32().or_else(|| value.get_int32().and_then(|value| u32::try_from(value).ok()));
+        let get_u8 = || get_u32().and_then(|value| u8::try_from(value).ok());
+
+        match key {
+            "application" => {
+                self.application = match value.get_string().as_deref() {
+                    Some(name) => name.parse()?,
+                    None => value.get_int32().and_then(|value| Application::try_from(value).ok()).ok_or_else(invalid)?,
+                }
             }
-        } else {
-            Self::default()
+            "frame_duration" | "expert_frame_duration" => self.frame_duration = FrameDuration::try_from(value.get_float().ok_or_else(invalid)?)?,
+            "signal" => {
+                self.signal = match value.get_string().as_deref() {
+                    Some(name) => name.parse()?,
+                    None => value.get_int32().and_then(|value| Signal::try_from(value).ok()).ok_or_else(invalid)?,
+                }
+            }
+            "bit_rate" => self.bit_rate = Some(get_u32().ok_or_else(invalid)?).filter(|&bit_rate| bit_rate > 0),
+            "vbr" => {
+                self.vbr = match value.get_string().as_deref() {
+                    Some(name) => name.parse()?,
+                    // Legacy integer values
+                    None => match get_u32() {
+                        Some(0) => VbrMode::Cbr,
+                        Some(1) => VbrMode::Vbr,
+                        Some(2) => VbrMode::ConstrainedVbr,
+                        _ => return Err(invalid()),
+                    },
+                }
+            }
+            "max_bandwidth" | "bandwidth" => {
+                let bandwidth = match value.get_string().as_deref() {
+                    Some("auto") => None,
+                    Some(name) => Some(name.parse()?),
+                    None => match value.get_int32().ok_or_else(invalid)? {
+                        0 | opus_sys::OPUS_AUTO => None,
+                        value => Some(Bandwidth::try_from(value).map_err(|_| invalid())?),
+                    },
+                };
+
+                if key == "bandwidth" {
+                    self.bandwidth = bandwidth;
+                } else {
+                    self.max_bandwidth = bandwidth;
+                }
+            }
+            "complexity" => self.complexity = get_u8().ok_or_else(invalid)?,
+            // "packet_loss" is the legacy name
+            "packet_loss_percent" | "packet_loss" => self.packet_loss_percent = get_u8().ok_or_else(invalid)?,
+            "fec" => self.fec = get_bool().ok_or_else(invalid)?,
+            "dtx" => self.dtx = get_bool().ok_or_else(invalid)?,
+            "keep_dtx_packets" => self.keep_dtx_packets = get_bool().ok_or_else(invalid)?,
+            "dred_duration" => self.dred_duration = get_u8().ok_or_else(invalid)?,
+            "packet_size" => self.packet_size = Some(get_u32().ok_or_else(invalid)?).filter(|&size| size > 0),
+            "force_channels" => self.force_channels = Some(get_u8().ok_or_else(invalid)?).filter(|&channels| channels > 0),
+            "lsb_depth" => self.lsb_depth = get_u8().ok_or_else(invalid)?,
+            "prediction_disabled" => self.prediction_disabled = get_bool().ok_or_else(invalid)?,
+            "phase_inversion_disabled" => self.phase_inversion_disabled = get_bool().ok_or_else(invalid)?,
+            _ => return Ok(false),
         }
+
+        Ok(true)
     }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(bit_rate) = self.bit_rate {
+            if i32::try_from(bit_rate).is_err() {
+                return Err(Error::Invalid(format!("bit_rate {}", bit_rate).into()));
+            }
+        }
+
+        if self.complexity > 10 {
+            return Err(Error::Invalid(format!("complexity {} out of 0..=10", self.complexity).into()));
+        }
+
+        if self.packet_loss_percent > 100 {
+            return Err(Error::Invalid(format!("packet_loss_percent {} out of 0..=100", self.packet_loss_percent).into()));
+        }
+
+        if self.dred_duration > 100 {
+            return Err(Error::Invalid(format!("dred_duration {} out of 0..=100", self.dred_duration).into()));
+        }
+
+        if let Some(channels) = self.force_channels {
+            if channels > 2 {
+                return Err(Error::Invalid(format!("force_channels {} out of 1..=2", channels).into()));
+            }
+        }
+
+        // Frames shorter than 10ms are only supported by the CELT-only mode
+        if matches!(self.frame_duration, FrameDuration::Ms2_5 | FrameDuration::Ms5) && self.application != Application::LowDelay {
+            return Err(Error::Invalid(format!("frame_duration {}ms requires the lowdelay application", self.frame_duration.millis()).into()));
+        }
+
+        if !(8..=24).contains(&self.lsb_depth) {
+            return Err(Error::Invalid(format!("lsb_depth {} out of 8..=24", self.lsb_depth).into()));
+        }
+
+        Ok(())
+    }
+
+    pub fn to_variant(&self) -> Variant {
+        let mut variant = Variant::new_dict();
+
+        variant.dict_set("application", self.application.as_str().into());
+        variant.dict_set("frame_duration", self.frame_duration.millis().into());
+        variant.dict_set("signal", self.signal.as_str().into());
+        variant.dict_set("vbr", self.vbr.as_str().into());
+        variant.dict_set("complexity", (self.complexity as u32).into());
+        variant.dict_set("packet_loss_percent", (self.packet_loss_percent as u32).into());
+        variant.dict_set("fec", self.fec.into());
+        variant.dict_set("dtx", self.dtx.into());
+        variant.dict_set("keep_dtx_packets", self.keep_dtx_packets.into());
+        variant.dict_set("dred_duration", (self.dred_duration as u32).into());
+        variant.dict_set("lsb_depth", (self.lsb_depth as u32).into());
+        variant.dict_set("prediction_disabled", self.prediction_disabled.into());
+        variant.dict_set("phase_inversion_disabled", self.phase_inversion_disabled.into());
+
+        if let Some(bit_rate) = self.bit_rate {
+            variant.dict_set("bit_rate", bit_rate.into());
+        }
+
+        if let Some(max_bandwidth) = self.max_bandwidth {
+            variant.dict_set("max_bandwidth", max_bandwidth.as_str().into());
+        }
+
+        if let Some(bandwidth) = self.bandwidth {
+            variant.dict_set("bandwidth", bandwidth.as_str().into());
+        }
+
+        if let Some(packet_size) = self.packet_size {
+            variant.dict_set("packet_size", packet_size.into());
+        }
+
+        if let Some(channels) = self.force_channels {
+            variant.dict_set("force_channels", (channels as u32).into());
+        }
+
+        variant
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamLayout {
+    Single,
+    Surround,
+    Ambisonics,
+}
+
+enum RawEncoder {
+    Single(*mut opus_sys::OpusEncoder),
+    Multistream(*mut opus_sys::OpusMSEncoder),
+    Projection(*mut opus_sys::OpusProjectionEncoder),
 }
 
-struct OpusEncoder {
-    encoder: *mut opus_sys::OpusEncoder,
+impl RawEncoder {
+    fn ctl(&self, key: i32, value: i32) -> c_int {
+        match *self {
+            RawEncoder::Single(encoder) => unsafe { opus_sys::opus_encoder_ctl(encoder, key, value) },
+            RawEncoder::Multistream(encoder) => unsafe { opus_sys::opus_multistream_encoder_ctl(encoder, key, value) },
+            RawEncoder::Projection(encoder) => unsafe { opus_sys::opus_projection_encoder_ctl(encoder, key, value) },
+        }
+    }
+
+    fn ctl_get(&self, key: i32, value: &mut i32) -> c_int {
+        match *self {
+            RawEncoder::Single(encoder) => unsafe { opus_sys::opus_encoder_ctl(encoder, key, value as *mut i32) },
+            RawEncoder::Multistream(encoder) => unsafe { opus_sys::opus_multistream_encoder_ctl(encoder, key, value as *mut i32) },
+            RawEncoder::Projection(encoder) => unsafe { opus_sys::opus_projection_encoder_ctl(encoder, key, value as *mut i32) },
+        }
+    }
+
+    fn ctl_data(&self, key: i32, data: &[u8]) -> c_int {
+        let (data_ptr, data_len) = (data.as_ptr(), data.len() as opus_sys::opus_int32);
+
+        match *self {
+            RawEncoder::Single(encoder) => unsafe { opus_sys::opus_encoder_ctl(encoder, key, data_ptr, data_len) },
+            RawEncoder::Multistream(encoder) => unsafe { opus_sys::opus_multistream_encoder_ctl(encoder, key, data_ptr, data_len) },
+            RawEncoder::Projection(encoder) => unsafe { opus_sys::opus_projection_encoder_ctl(encoder, key, data_ptr, data_len) },
+        }
+    }
+
+    fn reset(&self) {
+        match *self {
+            RawEncoder::Single(encoder) => unsafe { opus_sys::opus_encoder_ctl(encoder, opus_sys::OPUS_RESET_STATE) },
+            RawEncoder::Multistream(encoder) => unsafe { opus_sys::opus_multistream_encoder_ctl(encoder, opus_sys::OPUS_RESET_STATE) },
+            RawEncoder::Projection(encoder) => unsafe { opus_sys::opus_projection_encoder_ctl(encoder, opus_sys::OPUS_RESET_STATE) },
+        };
+    }
+
+    fn encode(&self, pcm: &[i16], frame_size: c_int, data: &mut [u8]) -> i32 {
+        match *self {
+            RawEncoder::Single(encoder) => unsafe {
+                opus_sys::opus_encode(encoder, pcm.as_ptr(), frame_size, data.as_mut_ptr(), data.len() as opus_sys::opus_int32)
+            },
+            RawEncoder::Multistream(encoder) => unsafe {
+                opus_sys::opus_multistream_encode(encoder, pcm.as_ptr(), frame_size, data.as_mut_ptr(), data.len() as opus_sys::opus_int32)
+            },
+            RawEncoder::Projection(encoder) => unsafe {
+                opus_sys::opus_projection_encode(encoder, pcm.as_ptr(), frame_size, data.as_mut_ptr(), data.len() as opus_sys::opus_int32)
+            },
+        }
+    }
+
+    fn encode_float(&self, pcm: &[f32], frame_size: c_int, data: &mut [u8]) -> i32 {
+        match *self {
+            RawEncoder::Single(encoder) => unsafe {
+                opus_sys::opus_encode_float(encoder, pcm.as_ptr(), frame_size, data.as_mut_ptr(), data.len() as opus_sys::opus_int32)
+            },
+            RawEncoder::Multistream(encoder) => unsafe {
+                opus_sys::opus_multistream_encode_float(encoder, pcm.as_ptr(), frame_size, data.as_mut_ptr(), data.len() as opus_sys::opus_int32)
+            },
+            RawEncoder::Projection(encoder) => unsafe {
+                opus_sys::opus_projection_encode_float(encoder, pcm.as_ptr(), frame_size, data.as_mut_ptr(), data.len() as opus_sys::opus_int32)
+            },
+        }
+    }
+}
+
+impl RawEncoder {
+    fn demixing_matrix(&self) -> Result<Vec<u8>> {
+        let RawEncoder::Projection(encoder) = *self else {
+            return Ok(Vec::new());
+        };
+
+        let mut size = 0;
+        let ret = self.ctl_get(opus_sys::OPUS_PROJECTION_GET_DEMIXING_MATRIX_SIZE_REQUEST, &mut size);
+        if ret != opus_sys::OPUS_OK {
+            return Err(Error::Failed(opus_error_string(ret)));
+        }
+
+        let mut matrix = vec![0u8; size as usize];
+        let ret = unsafe {
+            opus_sys::opus_projection_encoder_ctl(encoder, opus_sys::OPUS_PROJECTION_GET_DEMIXING_MATRIX_REQUEST, matrix.as_mut_ptr(), size)
+        };
+        if ret != opus_sys::OPUS_OK {
+            return Err(Error::Failed(opus_error_string(ret)));
+        }
+
+        Ok(matrix)
+    }
+}
+
+impl Drop for RawEncoder {
+    fn drop(&mut self) {
+        match *self {
+            RawEncoder::Single(encoder) => unsafe { opus_sys::opus_encoder_destroy(encoder) },
+            RawEncoder::Multistream(encoder) => unsafe { opus_sys::opus_multistream_encoder_destroy(encoder) },
+            RawEncoder::Projection(encoder) => unsafe { opus_sys::opus_projection_encoder_destroy(encoder) },
+        }
+    }
+}
+
+pub struct OpusEncoder {
+    encoder: RawEncoder,
     pending: VecDeque<Packet<'static>>,
-    options: OpusOptions,
-    buffer: Vec<u8>,
+    config: OpusEncoderConfig,
+    // Number of samples per frame at the encoder sample rate
+    frame_size: u32,
+    // Interleaved samples not yet encoded
+    fifo: Vec<u8>,
+    // Position of the first sample in the FIFO, in samples
+    position: i64,
+    time_base: Option<Rational64>,
+    started: bool,
+    // Trailing samples of the last drained packets that are padding, at 48kHz
+    end_padding: u32,
+    sample_format: SampleFormat,
+    sample_rate: u32,
+    channels: u8,
+    // Input channel order expected by the mapping family, if it differs from
+    // the channel layout order
+    channel_order: Option<Vec<usize>>,
+    // Scratch buffer for the reordered samples
+    reorder_buffer: Vec<u8>,
+    head: OpusHead,
+    extra_data: Vec<u8>,
+    dnn_blob: Vec<u8>,
 }
 
 unsafe impl Send for OpusEncoder {}
@@ -91,46 +532,25 @@ impl Codec<AudioEncoder> for OpusEncoder {
             let params: &AudioEncoderParameters = &params.try_into()?;
             self.set_audio_parameters(&params.audio)?;
             self.set_encoder_parameters(&params.encoder)?;
+            self.update_options()?;
         }
 
+        // The options go through set_config so that a new application is switched to
         if let Some(options) = options {
-            self.options = OpusOptions::from_variant(Some(options));
-            self.update_options()?;
+            let mut config = self.config.clone();
+            config.update_from_variant(options)?;
+            self.set_config(config)?;
+            self.update_extra_data()?;
         }
 
         Ok(())
     }
 
     fn set_option(&mut self, key: &str, value: &Variant) -> Result<()> {
-        let value = match value {
-            Variant::Bool(value) => *value as i32,
-            _ => value.get_int32().ok_or_else(|| invalid_param_error!(value))?,
-        };
+        let mut config = self.config.clone();
+        config.set(key, value)?;
 
-        match key {
-            "bit_rate" => self.encoder_ctl(opus_sys::OPUS_SET_BITRATE_REQUEST, value),
-            "packet_loss_percent" => {
-                self.options.packet_loss = value;
-                self.encoder_ctl(opus_sys::OPUS_SET_PACKET_LOSS_PERC_REQUEST, value)
-            }
-            "fec" => {
-                self.options.fec = value != 0;
-                self.encoder_ctl(opus_sys::OPUS_SET_INBAND_FEC_REQUEST, value)
-            }
-            "vbr" => {
-                self.options.vbr = value as u32;
-                self.encoder_ctl(opus_sys::OPUS_SET_VBR_REQUEST, value)
-            }
-            "max_bandwidth" => {
-                self.options.max_bandwidth = value as u32;
-                self.encoder_ctl(opus_sys::OPUS_SET_MAX_BANDWIDTH_REQUEST, value)
-            }
-            "complexity" => {
-                self.options.complexity = value as u32;
-                self.encoder_ctl(opus_sys::OPUS_SET_COMPLEXITY_REQUEST, value)
-            }
-            _ => Err(unsupported_error!(key)),
-        }
+        self.set_config(config)
     }
 }
 
@@ -142,6 +562,9 @@ const MAX_FRAME_SIZE: usize = 1275;
 const MAX_FRAMES: usize = 6;
 // The packet header size is 7 bytes
 const PACKET_HEADER_SIZE: usize = 7;
+// Packets of up to 2 bytes carry no audio data, which is how DTX frames are
+// encoded
+const DTX_PACKET_MAX_SIZE: usize = 2;
 
 impl Encoder<AudioEncoder> for OpusEncoder {
     fn send_frame(&mut self, _config: &AudioEncoder, pool: Option<&Arc<BufferPool>>, frame: SharedFrame<AudioFrame<'static>>) -> Result<()> {
@@ -154,25 +577,54 @@ impl Encoder<AudioEncoder> for OpusEncoder {
     }
 
     fn flush(&mut self, _config: &AudioEncoder) -> Result<()> {
-        self.buffer.fill(0);
+        if self.started {
+            self.drain()?;
+        } else {
+            self.end_padding = 0;
+        }
 
-        Ok(())
-    }
-}
+        // The reset keeps the other settings, and allows the application changed
+        // during the stream
+        self.encoder.reset();
+        self.encoder_ctl(opus_sys::OPUS_SET_APPLICATION_REQUEST, self.config.application as i32)?;
 
-impl Drop for OpusEncoder {
-    fn drop(&mut self) {
-        unsafe { opus_sys::opus_encoder_destroy(self.encoder) }
+        self.fifo.clear();
+        self.position = 0;
+        self.time_base = None;
+        self.started = false;
+
+        Ok(())
     }
 }
 
 impl OpusEncoder {
     pub fn new(codec_id: CodecID, parameters: &AudioEncoderParameters, options: Option<&Variant>) -> Result<Self> {
+        Self::new_with_layout(codec_id, parameters, options, StreamLayout::Single)
+    }
+
+    /// Creates an encoder backed by the multistream API, which supports
+    /// surround channel layouts using the channel mapping family 1
+    pub fn new_multistream(codec_id: CodecID, parameters: &AudioEncoderParameters, options: Option<&Variant>) -> Result<Self> {
+        Self::new_with_layout(codec_id, parameters, options, StreamLayout::Surround)
+    }
+
+    /// Creates an encoder backed by the projection API, which supports first to
+    /// third order ambisonics in ACN order using the channel mapping family 3.
+    /// Ambisonic channels have no speaker position, so the channel layout must
+    /// have an unspecified order
+    pub fn new_projection(codec_id: CodecID, parameters: &AudioEncoderParameters, options: Option<&Variant>) -> Result<Self> {
+        Self::new_with_layout(codec_id, parameters, options, StreamLayout::Ambisonics)
+    }
+
+    fn new_with_layout(codec_id: CodecID, parameters: &AudioEncoderParameters, options: Option<&Variant>, layout: StreamLayout) -> Result<Self> {
         if codec_id != CodecID::OPUS {
             return Err(unsupported_error!(codec_id));
         }
 
-        let mut opts = OpusOptions::from_variant(options);
+        let config = match options {
+            Some(options) => OpusEncoderConfig::from_variant(options)?,
+            None => OpusEncoderConfig::default(),
+        };
 
         let audio_params = &parameters.audio;
         let sample_format = audio_params.format.ok_or_else(|| invalid_param_error!(parameters))?;
@@ -182,46 +634,195 @@ impl OpusEncoder {
         }
 
         let sample_rate = audio_params.sample_rate.ok_or_else(|| invalid_param_error!(parameters))?.get() as opus_sys::opus_int32;
-        let channels = audio_params.channel_layout.as_ref().ok_or_else(|| invalid_param_error!(parameters))?.channels.get() as c_int;
-
-        // Calculate frame size in samples at 48kHz to validate frame duration
-        let frame_size = (opts.frame_duration * 48000f32 / 1000f32) as u32;
-        match frame_size {
-            // 2.5ms | 5ms
-            120 | 240 => {
-                if opts.application != opus_sys::OPUS_APPLICATION_RESTRICTED_LOWDELAY {
-                    opts.application = opus_sys::OPUS_APPLICATION_RESTRICTED_LOWDELAY;
+        let channel_layout = audio_params.channel_layout.as_ref().ok_or_else(|| invalid_param_error!(parameters))?;
+        let channels = channel_layout.channels.get() as c_int;
+
+        let frame_size = config.frame_duration.samples(sample_rate as u32);
+
+        let mut head = OpusHead::new(channels as u8, 0, sample_rate as u32);
+        let mut channel_order = None;
+        let mut error = 0;
+
+        let opus_encoder = match layout {
+            StreamLayout::Surround => {
+                // The mapping family 1 covers up to 8 channels in the Vorbis channel order
+                if channels > 8 {
+                    return Err(unsupported_error!(channels));
                 }
+
+                let mapping_family = if channels > 2 {
+                    1
+                } else {
+                    0
+                };
+
+                // The input channels are reordered to the Vorbis channel order expected by the
+                // mapping
+                if mapping_family == 1 {
+                    channel_order = Some(vorbis_channel_order(channel_layout)?);
+                }
+                let mut streams = 0;
+                let mut coupled_streams = 0;
+                let mut mapping = vec![0u8; channels as usize];
+
+                let opus_encoder = unsafe {
+                    opus_sys::opus_multistream_surround_encoder_create(
+                        sample_rate,
+                        channels,
+                        mapping_family,
+                        &mut streams,
+                        &mut coupled_streams,
+                        mapping.as_mut_ptr(),
+                        config.application as i32,
+                        &mut error,
+                    )
+                };
+                if opus_encoder.is_null() || error != opus_sys::OPUS_OK {
+                    return Err(Error::CreationFailed(opus_error_string(error)));
+                }
+
+                head.mapping_family = mapping_family as u8;
+                head.stream_count = streams as u8;
+                head.coupled_count = coupled_streams as u8;
+                head.mapping = mapping;
+
+                RawEncoder::Multistream(opus_encoder)
             }
-            // 10ms | 20ms | 40ms | 60ms | 80ms | 100ms | 120ms
-            480 | 960 | 1920 | 2880 | 3840 | 4800 | 5760 => {}
-            _ => return Err(Error::Invalid("frame duration".into())),
-        }
+            StreamLayout::Ambisonics => {
+                // Ambisonics of order 1 to 3, optionally followed by a non-diegetic stereo pair
+                if !matches!(channels, 4 | 6 | 9 | 11 | 16 | 18) {
+                    return Err(unsupported_error!(channels));
+                }
 
-        opts.frame_size = frame_size * sample_rate as u32 / 48000;
+                // Speaker layouts with the same channel count are not ambisonics
+                if channel_layout.order != ChannelOrder::Unspecified {
+                    return Err(Error::Unsupported("speaker channel layout for ambisonics".into()));
+                }
 
-        let mut error = 0;
-        let opus_encoder = unsafe { opus_sys::opus_encoder_create(sample_rate, channels, opts.application, &mut error) };
-        if opus_encoder.is_null() || error != opus_sys::OPUS_OK {
-            return Err(Error::CreationFailed(opus_error_string(error)));
-        }
+                let mut streams = 0;
+                let mut coupled_streams = 0;
+                let opus_encoder = unsafe {
+                    opus_sys::opus_projection_ambisonics_encoder_create(
+                        sample_rate,
+                        channels,
+                        3,
+                        &mut streams,
+                        &mut coupled_streams,
+                        config.application as i32,
+                        &mut error,
+                    )
+                };
+                if opus_encoder.is_null() || error != opus_sys::OPUS_OK {
+                    return Err(Error::CreationFailed(opus_error_string(error)));
+                }
+
+                let opus_encoder = RawEncoder::Projection(opus_encoder);
+                let mut gain = 0;
+                let ret = opus_encoder.ctl_get(opus_sys::OPUS_PROJECTION_GET_DEMIXING_MATRIX_GAIN_REQUEST, &mut gain);
+                if ret != opus_sys::OPUS_OK {
+                    return Err(Error::Failed(opus_error_string(ret)));
+                }
+
+                head.output_gain = gain as i16;
+                head.mapping_family = 3;
+                head.stream_count = streams as u8;
+                head.coupled_count = coupled_streams as u8;
+                head.mapping = Vec::new();
+                head.demixing_matrix = opus_encoder.demixing_matrix()?;
+
+                opus_encoder
+            }
+            StreamLayout::Single => {
+                let opus_encoder = unsafe { opus_sys::opus_encoder_create(sample_rate, channels, config.application as i32, &mut error) };
+                if opus_encoder.is_null() || error != opus_sys::OPUS_OK {
+                    return Err(Error::CreationFailed(opus_error_string(error)));
+                }
+
+                RawEncoder::Single(opus_encoder)
+            }
+        };
 
         let mut encoder: OpusEncoder = OpusEncoder {
             encoder: opus_encoder,
             pending: VecDeque::with_capacity(DEFAULT_PACKET_PENDING_CAPACITY),
-            options: opts,
-            buffer: vec![0u8; frame_size as usize * channels as usize * sample_format.bytes() as usize],
+            config,
+            frame_size,
+            fifo: Vec::with_capacity(frame_size as usize * channels as usize * sample_format.bytes() as usize),
+            position: 0,
+            time_base: None,
+            started: false,
+            end_padding: 0,
+            sample_format,
+            sample_rate: sample_rate as u32,
+            channels: channels as u8,
+            channel_order,
+            reorder_buffer: Vec::new(),
+            head,
+            extra_data: Vec::new(),
+            dnn_blob: Vec::new(),
         };
 
         encoder.set_audio_parameters(audio_params)?;
         encoder.set_encoder_parameters(&parameters.encoder)?;
         encoder.update_options()?;
+        encoder.update_extra_data()?;
 
         Ok(encoder)
     }
 
+    /// OpusHead identification header, as expected by Ogg, Matroska and MP4
+    /// muxers
+    pub fn extra_data(&self) -> &[u8] {
+        &self.extra_data
+    }
+
+    /// SDP format parameters describing the stream sent by this encoder, to
+    /// which the receiving preferences of the decoder are added for an offer or
+    /// answer
+    pub fn fmtp(&self) -> OpusFmtp {
+        // Every packet holds a single frame
+        let millis = self.config.frame_duration.millis();
+
+        OpusFmtp {
+            sprop_max_capture_rate: Some(self.sample_rate),
+            sprop_stereo: Some(self.channels == 2),
+            ptime: Some((millis as u32).max(1)),
+            max_ptime: Some(millis.ceil() as u32),
+            ..OpusFmtp::default()
+        }
+    }
+
+    /// OpusTags comment header with the libopus version as vendor string, to
+    /// which user comments can be added
+    pub fn tags(&self) -> OpusTags {
+        let vendor = unsafe { CStr::from_ptr(opus_sys::opus_get_version_string()) };
+        OpusTags::new(&vendor.to_string_lossy())
+    }
+
+    /// Loads the DNN weights used by Deep REDundancy, which only takes effect
+    /// with a non-zero `dred_duration` and expected packet loss
+    pub fn load_dnn_blob(&mut self, data: &[u8]) -> Result<()> {
+        // The encoder keeps referencing the weights, so they must outlive it
+        let dnn_blob = data.to_vec();
+        let ret = self.encoder.ctl_data(opus_sys::OPUS_SET_DNN_BLOB_REQUEST, &dnn_blob);
+
+        if ret != opus_sys::OPUS_OK {
+            return Err(Error::SetFailed(opus_error_string(ret)));
+        }
+
+        self.dnn_blob = dnn_blob;
+
+        Ok(())
+    }
+
+    pub fn load_dnn_blob_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let data = fs::read(path).map_err(|err| Error::Invalid(err.to_string().into()))?;
+
+        self.load_dnn_blob(&data)
+    }
+
     fn encoder_ctl(&mut self, key: i32, value: i32) -> Result<()> {
-        let ret = unsafe { opus_sys::opus_encoder_ctl(self.encoder, key, value) };
+        let ret = self.encoder.ctl(key, value);
 
         if ret != opus_sys::OPUS_OK {
             return Err(Error::SetFailed(opus_error_string(ret)));
@@ -230,20 +831,122 @@ impl OpusEncoder {
         Ok(())
     }
 
+    /// Number of padding samples at the end of the stream ended by the last
+    /// flush, after the encoder delay is removed, as needed for end trimming.
+    /// Like the granule positions, it is expressed at 48kHz
+    pub fn end_padding(&self) -> u32 {
+        self.end_padding
+    }
+
+    /// Bitrate targeted by the encoder, in bits per second
+    pub fn bit_rate(&self) -> Result<u32> {
+        Ok(self.encoder_ctl_get(opus_sys::OPUS_GET_BITRATE_REQUEST)? as u32)
+    }
+
+    /// Number of samples the encoder delays the input by, at the encoder sample
+    /// rate
+    pub fn lookahead(&self) -> Result<u32> {
+        Ok(self.encoder_ctl_get(opus_sys::OPUS_GET_LOOKAHEAD_REQUEST)? as u32)
+    }
+
+    /// Final state of the range coder for the last encoded packet, which
+    /// matches the decoder one when the packet is decoded correctly
+    pub fn final_range(&self) -> Result<u32> {
+        Ok(self.encoder_ctl_get(opus_sys::OPUS_GET_FINAL_RANGE_REQUEST)? as u32)
+    }
+
+    /// Bandwidth of the last encoded packet, or `None` before the first one
+    pub fn bandwidth(&self) -> Result<Option<Bandwidth>> {
+        Ok(Bandwidth::try_from(self.encoder_ctl_get(opus_sys::OPUS_GET_BANDWIDTH_REQUEST)?).ok())
+    }
+
+    /// Whether the encoder is in DTX because of a silent or noise-only input
+    pub fn in_dtx(&self) -> Result<bool> {
+        Ok(self.encoder_ctl_get(opus_sys::OPUS_GET_IN_DTX_REQUEST)? != 0)
+    }
+
+    fn encoder_ctl_get(&self, key: i32) -> Result<i32> {
+        let mut value = 0;
+        let ret = self.encoder.ctl_get(key, &mut value);
+
+        if ret != opus_sys::OPUS_OK {
+            return Err(Error::Failed(opus_error_string(ret)));
+        }
+
+        Ok(value)
+    }
+
+    fn update_extra_data(&mut self) -> Result<()> {
+        let lookahead = self.encoder_ctl_get(opus_sys::OPUS_GET_LOOKAHEAD_REQUEST)?;
+        // Pre-skip is expressed at 48kHz
+        let pre_skip = (lookahead as u64 * 48000 / self.sample_rate as u64) as u16;
+
+        self.head.pre_skip = pre_skip;
+        self.extra_data = self.head.to_bytes();
+
+        Ok(())
+    }
+
     fn set_audio_parameters(&mut self, _audio_params: &AudioParameters) -> Result<()> {
         Ok(())
     }
 
+    /// Current configuration, as updated by the options and parameters
+    pub fn config(&self) -> &OpusEncoderConfig {
+        &self.config
+    }
+
+    /// Replaces the configuration. A new frame duration applies to the samples
+    /// not yet buffered
+    pub fn set_config(&mut self, config: OpusEncoderConfig) -> Result<()> {
+        config.validate()?;
+
+        if config.application != self.config.application {
+            self.set_application(config.application)?;
+        }
+
+        self.frame_size = config.frame_duration.samples(self.sample_rate);
+        self.config = config;
+        self.update_options()
+    }
+
+    /// Switches between the VoIP and Audio applications, e.g. when a call turns
+    /// into music, without recreating the encoder. In the middle of a stream,
+    /// the switch takes effect at the next flush, which keeps the stream
+    /// continuous. The low-delay application changes the encoder delay, so it
+    /// can only be chosen at creation
+    pub fn set_application(&mut self, application: Application) -> Result<()> {
+        if application == self.config.application {
+            return Ok(());
+        }
+
+        if application == Application::LowDelay || self.config.application == Application::LowDelay {
+            return Err(Error::Unsupported("switching to or from the lowdelay application".into()));
+        }
+
+        // libopus only accepts a new application until the first frame is encoded
+        // after a reset, so a started stream applies it when flushed
+        if !self.started {
+            self.encoder_ctl(opus_sys::OPUS_SET_APPLICATION_REQUEST, application as i32)?;
+        }
+
+        self.config.application = application;
+
+        Ok(())
+    }
+
     fn set_encoder_parameters(&mut self, encoder_params: &EncoderParameters) -> Result<()> {
+        // libopus clamps the bitrate to the range supported by the channel count
         if let Some(bit_rate) = encoder_params.bit_rate {
-            self.encoder_ctl(opus_sys::OPUS_SET_BITRATE_REQUEST, bit_rate as i32)?;
+            let bit_rate = i32::try_from(bit_rate).map_err(|_| Error::Invalid(format!("bit_rate {}", bit_rate).into()))?;
+            self.config.bit_rate = Some(bit_rate as u32).filter(|&bit_rate| bit_rate > 0);
         }
 
         if let Some(level) = encoder_params.level {
-            self.options.complexity = if !(0..=10).contains(&level) {
+            self.config.complexity = if !(0..=10).contains(&level) {
                 10
             } else {
-                level as u32
+                level as u8
             };
         }
 
@@ -251,115 +954,221 @@ impl OpusEncoder {
     }
 
     fn update_options(&mut self) -> Result<()> {
-        self.encoder_ctl(opus_sys::OPUS_SET_VBR_REQUEST, (self.options.vbr > 0) as i32)?;
-        self.encoder_ctl(opus_sys::OPUS_SET_VBR_CONSTRAINT_REQUEST, (self.options.vbr == 2) as i32)?;
-        self.encoder_ctl(opus_sys::OPUS_SET_PACKET_LOSS_PERC_REQUEST, self.options.packet_loss)?;
-        self.encoder_ctl(opus_sys::OPUS_SET_INBAND_FEC_REQUEST, self.options.fec as i32)?;
+        let config = &self.config;
+        let ctls = [
+            (opus_sys::OPUS_SET_VBR_REQUEST, (config.vbr != VbrMode::Cbr) as i32),
+            (opus_sys::OPUS_SET_VBR_CONSTRAINT_REQUEST, (config.vbr == VbrMode::ConstrainedVbr) as i32),
+            (opus_sys::OPUS_SET_SIGNAL_REQUEST, config.signal as i32),
+            (opus_sys::OPUS_SET_BITRATE_REQUEST, config.bit_rate.map_or(opus_sys::OPUS_AUTO, |bit_rate| bit_rate as i32)),
+            (opus_sys::OPUS_SET_COMPLEXITY_REQUEST, config.complexity as i32),
+            (opus_sys::OPUS_SET_PACKET_LOSS_PERC_REQUEST, config.packet_loss_percent as i32),
+            (opus_sys::OPUS_SET_INBAND_FEC_REQUEST, config.fec as i32),
+            (opus_sys::OPUS_SET_DTX_REQUEST, config.dtx as i32),
+            (opus_sys::OPUS_SET_MAX_BANDWIDTH_REQUEST, config.max_bandwidth.unwrap_or(Bandwidth::Fullband) as i32),
+            (opus_sys::OPUS_SET_BANDWIDTH_REQUEST, config.bandwidth.map_or(opus_sys::OPUS_AUTO, |bandwidth| bandwidth as i32)),
+            (opus_sys::OPUS_SET_FORCE_CHANNELS_REQUEST, config.force_channels.map_or(opus_sys::OPUS_AUTO, |channels| channels as i32)),
+            (opus_sys::OPUS_SET_LSB_DEPTH_REQUEST, config.lsb_depth as i32),
+            (opus_sys::OPUS_SET_PREDICTION_DISABLED_REQUEST, config.prediction_disabled as i32),
+            (opus_sys::OPUS_SET_PHASE_INVERSION_DISABLED_REQUEST, config.phase_inversion_disabled as i32),
+            // Frames are always passed to libopus with the configured duration
+            (opus_sys::OPUS_SET_EXPERT_FRAME_DURATION_REQUEST, config.frame_duration.expert_frame_duration()),
+        ];
 
-        if self.options.complexity > 0 {
-            self.encoder_ctl(opus_sys::OPUS_SET_COMPLEXITY_REQUEST, self.options.complexity as i32)?;
+        for (key, value) in ctls {
+            self.encoder_ctl(key, value)?;
         }
 
-        if self.options.max_bandwidth > 0 {
-            self.encoder_ctl(opus_sys::OPUS_SET_MAX_BANDWIDTH_REQUEST, self.options.max_bandwidth as i32)?;
+        // DRED is only available when libopus is built with it
+        if self.config.dred_duration > 0 {
+            self.encoder_ctl(opus_sys::OPUS_SET_DRED_DURATION_REQUEST, self.config.dred_duration as i32)?;
         }
 
         Ok(())
     }
 
+    fn sample_size(&self) -> usize {
+        self.channels as usize * self.sample_format.bytes() as usize
+    }
+
+    fn samples_to_time(&self, samples: i64, time_base: Rational64) -> i64 {
+        (Rational64::new(samples, self.sample_rate as i64) / time_base).to_integer()
+    }
+
     fn encode(&mut self, frame: SharedFrame<AudioFrame<'static>>, pool: Option<&Arc<BufferPool>>) -> Result<()> {
         let frame = frame.read();
         let desc = frame.descriptor();
         let sample_format = desc.format;
 
-        if sample_format != SampleFormat::S16 && sample_format != SampleFormat::F32 {
+        if sample_format != self.sample_format {
             return Err(unsupported_error!(sample_format));
         }
 
+        let channels = desc.channels().get();
+        if channels != self.channels {
+            return Err(invalid_param_error!(channels));
+        }
+
         let guard = frame.map().map_err(|_| Error::Invalid("not readable".into()))?;
         let planes = guard.planes().unwrap();
-        let packet_size = PACKET_HEADER_SIZE + MAX_FRAME_SIZE * MAX_FRAMES;
-        let channels = desc.channels().get() as usize;
-        let sample_size = channels * sample_format.bytes() as usize;
+        let sample_size = self.sample_size();
         let frame_data = planes.plane_data(0).unwrap();
         let frame_data_size = desc.samples.get() as usize * sample_size;
-        let chunk_size = self.options.frame_size as usize * sample_size;
-        let mut pts = frame.pts.unwrap_or(0);
 
-        self.buffer.fill(0);
+        // Buffered samples keep their timestamps so that the packet pts stay continuous
+        if self.fifo.is_empty() {
+            if let Some(time_base) = frame.time_base {
+                self.time_base = Some(time_base);
+            }
 
-        for chunk in frame_data[..frame_data_size].chunks(chunk_size) {
-            let mut packet = if let Some(pool) = pool {
-                Packet::from_buffer(pool.get_buffer_with_length(packet_size))
-            } else {
-                Packet::new(packet_size)
-            };
+            if let Some(pts) = frame.pts {
+                let time_base = self.time_base.unwrap_or_else(|| Rational64::new(1, self.sample_rate as i64));
+                self.position = (time_base * pts * self.sample_rate as i64).to_integer();
+            }
+        }
 
-            let packet_data = packet.data_mut().ok_or_else(|| Error::Invalid("packet not writable".into()))?;
-            let data = if chunk.len() < chunk_size {
-                self.buffer[..chunk.len()].copy_from_slice(chunk);
-                self.buffer.as_slice()
-            } else {
-                chunk
-            };
+        // A new stream starts after a flush
+        if !self.started {
+            self.end_padding = 0;
+        }
 
-            let ret = match desc.format {
-                SampleFormat::S16 => {
-                    let data = bytemuck::cast_slice::<u8, i16>(data);
-                    unsafe {
-                        opus_sys::opus_encode(
-                            self.encoder,
-                            data.as_ptr(),
-                            (data.len() / channels) as i32,
-                            packet_data.as_mut_ptr(),
-                            packet_data.len() as i32,
-                        )
-                    }
-                }
-                SampleFormat::F32 => {
-                    let data = bytemuck::cast_slice::<u8, f32>(data);
-                    unsafe {
-                        opus_sys::opus_encode_float(
-                            self.encoder,
-                            data.as_ptr(),
-                            (data.len() / channels) as i32,
-                            packet_data.as_mut_ptr(),
-                            packet_data.len() as i32,
-                        )
-                    }
-                }
-                _ => return Err(unsupported_error!(sample_format)),
-            };
+        self.fifo.extend_from_slice(&frame_data[..frame_data_size]);
+        self.started = true;
 
-            if ret < 0 {
-                return Err(Error::Failed(opus_error_string(ret)));
-            }
+        let chunk_size = self.frame_size as usize * sample_size;
+        let mut fifo = mem::take(&mut self.fifo);
+        let mut chunks = fifo.chunks_exact(chunk_size);
 
-            let samples = self.options.frame_size as i64;
-            let (duration, time_base) = if let Some(time_base) = frame.time_base {
-                let duration = (Rational64::new(samples, desc.sample_rate.get() as i64) / time_base).to_integer();
-                (duration, time_base)
-            } else {
-                let time_base = Rational64::new(1, desc.sample_rate.get() as i64);
-                let duration = samples;
-                (duration, time_base)
-            };
+        for chunk in &mut chunks {
+            self.encode_chunk(chunk, pool)?;
+        }
+
+        let consumed = fifo.len() - chunks.remainder().len();
+        fifo.drain(..consumed);
+        self.fifo = fifo;
+
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        let sample_size = self.sample_size();
+        let frame_size = self.frame_size as usize;
+        let lookahead = self.encoder_ctl_get(opus_sys::OPUS_GET_LOOKAHEAD_REQUEST)? as usize;
+
+        // Encode enough silence after the remaining samples to push the lookahead out
+        let samples = self.fifo.len() / sample_size + lookahead;
+        let padded_samples = samples.div_ceil(frame_size) * frame_size;
+        let mut fifo = mem::take(&mut self.fifo);
+        fifo.resize(padded_samples * sample_size, 0);
+
+        for chunk in fifo.chunks_exact(frame_size * sample_size) {
+            self.encode_chunk(chunk, None)?;
+        }
+
+        // End trimming is expressed at 48kHz
+        self.end_padding = ((padded_samples - samples) as u64 * 48000 / self.sample_rate as u64) as u32;
+
+        Ok(())
+    }
+
+    fn encode_chunk(&mut self, data: &[u8], pool: Option<&Arc<BufferPool>>) -> Result<()> {
+        let max_packet_size = (PACKET_HEADER_SIZE + MAX_FRAME_SIZE * MAX_FRAMES) * self.head.stream_count as usize;
+        let fixed_size = self.config.packet_size.unwrap_or(0) as usize;
+        let packet_size = max_packet_size.max(fixed_size);
+        let channels = self.channels as usize;
+        let mut packet = if let Some(pool) = pool {
+            Packet::from_buffer(pool.get_buffer_with_length(packet_size))
+        } else {
+            Packet::new(packet_size)
+        };
+
+        let mut reorder_buffer = mem::take(&mut self.reorder_buffer);
+        let data = if let Some(channel_order) = &self.channel_order {
+            reorder_channels(data, &mut reorder_buffer, channel_order, self.sample_format.bytes() as usize);
+            reorder_buffer.as_slice()
+        } else {
+            data
+        };
+
+        let packet_data = packet.data_mut().ok_or_else(|| Error::Invalid("packet not writable".into()))?;
+        // Limit the encoded size to the fixed packet size, the rest is padding
+        let packet_data = if fixed_size > 0 {
+            &mut packet_data[..fixed_size]
+        } else {
+            packet_data
+        };
+        let ret = match self.sample_format {
+            SampleFormat::S16 => {
+                let data = bytemuck::cast_slice::<u8, i16>(data);
+                self.encoder.encode(data, (data.len() / channels) as c_int, packet_data)
+            }
+            SampleFormat::F32 => {
+                let data = bytemuck::cast_slice::<u8, f32>(data);
+                self.encoder.encode_float(data, (data.len() / channels) as c_int, packet_data)
+            }
+            _ => return Err(unsupported_error!(self.sample_format)),
+        };
 
-            packet.pts = Some(pts);
-            packet.duration = Some(duration);
-            packet.time_base = Some(time_base);
-            pts += duration;
+        self.reorder_buffer = reorder_buffer;
 
-            packet.truncate(ret as usize)?;
+        if ret < 0 {
+            return Err(Error::Failed(opus_error_string(ret)));
+        }
 
-            self.pending.push_back(packet);
+        // Frames that need not be transmitted during DTX leave a gap in the timestamps
+        if self.config.dtx &&
+            !self.config.keep_dtx_packets &&
+            ret as usize <= DTX_PACKET_MAX_SIZE &&
+            self.encoder_ctl_get(opus_sys::OPUS_GET_IN_DTX_REQUEST)? != 0
+        {
+            self.position += self.frame_size as i64;
+            return Ok(());
         }
 
+        let len = if fixed_size > 0 {
+            let packet_data = &mut packet.data_mut().ok_or_else(|| Error::Invalid("packet not writable".into()))?[..fixed_size];
+
+            match self.encoder {
+                RawEncoder::Single(_) => pad(packet_data, ret as usize)?,
+                _ => multistream_pad(packet_data, ret as usize, self.head.stream_count)?,
+            }
+
+            fixed_size
+        } else {
+            ret as usize
+        };
+
+        let samples = self.frame_size as i64;
+        let time_base = self.time_base.unwrap_or_else(|| Rational64::new(1, self.sample_rate as i64));
+        let pts = self.samples_to_time(self.position, time_base);
+
+        packet.pts = Some(pts);
+        packet.duration = Some(self.samples_to_time(self.position + samples, time_base) - pts);
+        packet.time_base = Some(time_base);
+        self.position += samples;
+
+        packet.truncate(len)?;
+
+        self.pending.push_back(packet);
+
         Ok(())
     }
 }
 
+fn reorder_channels(src: &[u8], dst: &mut Vec<u8>, channel_order: &[usize], sample_bytes: usize) {
+    let sample_size = channel_order.len() * sample_bytes;
+
+    dst.clear();
+
+    for sample in src.chunks_exact(sample_size) {
+        for &channel in channel_order {
+            dst.extend_from_slice(&sample[channel * sample_bytes..(channel + 1) * sample_bytes]);
+        }
+    }
+}
+
 const CODEC_NAME: &str = "opus-enc";
+const MULTISTREAM_CODEC_NAME: &str = "opus-ms-enc";
+const PROJECTION_CODEC_NAME: &str = "opus-proj-enc";
 
 pub struct OpusEncoderBuilder;
 
@@ -379,17 +1188,273 @@ impl CodecBuilder<AudioEncoder> for OpusEncoderBuilder {
     }
 }
 
+pub struct OpusMultistreamEncoderBuilder;
+
+impl EncoderBuilder<AudioEncoder> for OpusMultistreamEncoderBuilder {
+    fn new_encoder(&self, codec_id: CodecID, params: &CodecParameters, options: Option<&Variant>) -> Result<Box<dyn Encoder<AudioEncoder>>> {
+        Ok(Box::new(OpusEncoder::new_multistream(codec_id, &params.try_into()?, options)?))
+    }
+}
+
+impl CodecBuilder<AudioEncoder> for OpusMultistreamEncoderBuilder {
+    fn id(&self) -> CodecID {
+        CodecID::OPUS
+    }
+
+    fn name(&self) -> &'static str {
+        MULTISTREAM_CODEC_NAME
+    }
+}
+
+pub struct OpusProjectionEncoderBuilder;
+
+impl EncoderBuilder<AudioEncoder> for OpusProjectionEncoderBuilder {
+    fn new_encoder(&self, codec_id: CodecID, params: &CodecParameters, options: Option<&Variant>) -> Result<Box<dyn Encoder<AudioEncoder>>> {
+        Ok(Box::new(OpusEncoder::new_projection(codec_id, &params.try_into()?, options)?))
+    }
+}
+
+impl CodecBuilder<AudioEncoder> for OpusProjectionEncoderBuilder {
+    fn id(&self) -> CodecID {
+        CodecID::OPUS
+    }
+
+    fn name(&self) -> &'static str {
+        PROJECTION_CODEC_NAME
+    }
+}
+
+/// OpusHead identification header of the stream that the encoder registered
+/// under `name` produces with these parameters and options, for muxers that
+/// only hold the encoder through an `EncoderContext`
+pub fn encoder_extra_data(name: &str, params: &CodecParameters, options: Option<&Variant>) -> Result<Vec<u8>> {
+    let params = &params.try_into()?;
+    let encoder = match name {
+        CODEC_NAME => OpusEncoder::new(CodecID::OPUS, params, options)?,
+        MULTISTREAM_CODEC_NAME => OpusEncoder::new_multistream(CodecID::OPUS, params, options)?,
+        PROJECTION_CODEC_NAME => OpusEncoder::new_projection(CodecID::OPUS, params, options)?,
+        _ => return Err(not_found_error!(name)),
+    };
+
+    Ok(encoder.extra_data)
+}
+
 impl CodecInformation for OpusEncoder {
     fn id(&self) -> CodecID {
         CodecID::OPUS
     }
 
     fn name(&self) -> &'static str {
-        CODEC_NAME
+        match self.encoder {
+            RawEncoder::Single(_) => CODEC_NAME,
+            RawEncoder::Multistream(_) => MULTISTREAM_CODEC_NAME,
+            RawEncoder::Projection(_) => PROJECTION_CODEC_NAME,
+        }
     }
 }
 
 #[ctor]
 pub fn initialize() {
     register_encoder(Arc::new(OpusEncoderBuilder), false);
+    register_encoder(Arc::new(OpusMultistreamEncoderBuilder), false);
+    register_encoder(Arc::new(OpusProjectionEncoderBuilder), false);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use media_core::audio::ChannelLayout;
+
+    use super::*;
+
+    #[test]
+    fn config_variant_round_trip() {
+        let default = OpusEncoderConfig::default();
+        assert_eq!(OpusEncoderConfig::from_variant(&default.to_variant()).unwrap(), default);
+
+        let config = OpusEncoderConfig {
+            application: Application::LowDelay,
+            frame_duration: FrameDuration::Ms2_5,
+            signal: Signal::Music,
+            bit_rate: Some(640000),
+            vbr: VbrMode::ConstrainedVbr,
+            max_bandwidth: Some(Bandwidth::SuperWideband),
+            bandwidth: Some(Bandwidth::Wideband),
+            complexity: 5,
+            packet_loss_percent: 20,
+            fec: true,
+            dtx: true,
+            keep_dtx_packets: true,
+            dred_duration: 10,
+            packet_size: Some(160),
+            force_channels: Some(1),
+            lsb_depth: 16,
+            prediction_disabled: true,
+            phase_inversion_disabled: true,
+        };
+        assert_eq!(OpusEncoderConfig::from_variant(&config.to_variant()).unwrap(), config);
+    }
+
+    #[test]
+    fn config_unknown_keys() {
+        let mut options = Variant::new_dict();
+        options.dict_set("complexity", 3u32.into());
+        options.dict_set("sample_rate", 48000u32.into());
+
+        let mut config = OpusEncoderConfig::default();
+        config.update_from_variant(&options).unwrap();
+        assert_eq!(config.complexity, 3);
+
+        assert!(config.set("sample_rate", &48000u32.into()).is_err());
+    }
+
+    #[test]
+    fn config_invalid_values() {
+        let mut config = OpusEncoderConfig::default();
+
+        // A rejected dictionary leaves the configuration unchanged
+        let mut options = Variant::new_dict();
+        options.dict_set("complexity", 3u32.into());
+        options.dict_set("packet_loss_percent", 101u32.into());
+        assert!(config.update_from_variant(&options).is_err());
+        assert_eq!(config, OpusEncoderConfig::default());
+
+        assert!(config.set("vbr", &"variable".into()).is_err());
+        assert!(config.set("fec", &"yes".into()).is_err());
+
+        // Frames shorter than 10ms need the lowdelay application
+        let mut options = Variant::new_dict();
+        options.dict_set("frame_duration", 5.0f32.into());
+        assert!(config.update_from_variant(&options).is_err());
+        options.dict_set("application", "lowdelay".into());
+        config.update_from_variant(&options).unwrap();
+        assert_eq!(config.frame_duration, FrameDuration::Ms5);
+    }
+
+    #[test]
+    fn configure_application() {
+        let params = AudioEncoderParameters {
+            audio: AudioParameters {
+                format: Some(SampleFormat::S16),
+                samples: None,
+                sample_rate: NonZeroU32::new(48000),
+                channel_layout: Some(ChannelLayout::STEREO),
+            },
+            encoder: EncoderParameters::default(),
+        };
+        let mut encoder = OpusEncoder::new(CodecID::OPUS, &params, None).unwrap();
+
+        let mut options = Variant::new_dict();
+        options.dict_set("application", "voip".into());
+        encoder.configure(None, Some(&options)).unwrap();
+        assert_eq!(encoder.config().application, Application::VoIP);
+        assert_eq!(encoder.encoder_ctl_get(opus_sys::OPUS_GET_APPLICATION_REQUEST).unwrap(), Application::VoIP as i32);
+
+        // The lowdelay application can only be chosen at creation
+        options.dict_set("application", "lowdelay".into());
+        assert!(encoder.configure(None, Some(&options)).is_err());
+        assert_eq!(encoder.config().application, Application::VoIP);
+    }
+
+    fn stereo_encoder() -> (OpusEncoder, AudioEncoder) {
+        let audio = AudioParameters {
+            format: Some(SampleFormat::S16),
+            samples: None,
+            sample_rate: NonZeroU32::new(48000),
+            channel_layout: Some(ChannelLayout::STEREO),
+        };
+        let params = AudioEncoderParameters {
+            audio: audio.clone(),
+            encoder: EncoderParameters::default(),
+        };
+        let config = AudioEncoder {
+            audio,
+            encoder: EncoderParameters::default(),
+            frame_size: None,
+            delay: None,
+        };
+
+        (OpusEncoder::new(CodecID::OPUS, &params, None).unwrap(), config)
+    }
+
+    #[test]
+    fn switch_application() {
+        let (mut encoder, config) = stereo_encoder();
+        let mut packets = Vec::new();
+
+        for index in 0..10 {
+            // The switch is deferred to the end of the stream
+            if index == 5 {
+                encoder.set_application(Application::VoIP).unwrap();
+                assert_eq!(encoder.encoder_ctl_get(opus_sys::OPUS_GET_APPLICATION_REQUEST).unwrap(), Application::Audio as i32);
+            }
+
+            let frame = AudioFrame::new(SampleFormat::S16, 2, 960, 48000).unwrap();
+            encoder.send_frame(&config, None, SharedFrame::<AudioFrame<'static>>::new(frame)).unwrap();
+        }
+
+        encoder.flush(&config).unwrap();
+        while let Ok(packet) = encoder.receive_packet(&config, None) {
+            packets.push(packet);
+        }
+
+        // All the input and the lookahead are encoded in consecutive packets
+        let mut position = 0;
+        for packet in &packets {
+            assert_eq!(packet.pts, Some(position));
+            position += packet.duration.unwrap();
+        }
+        let samples = 10 * 960 + encoder.lookahead().unwrap();
+        assert_eq!(position, (samples.div_ceil(960) * 960) as i64);
+
+        assert_eq!(encoder.config().application, Application::VoIP);
+        assert_eq!(encoder.encoder_ctl_get(opus_sys::OPUS_GET_APPLICATION_REQUEST).unwrap(), Application::VoIP as i32);
+    }
+
+    #[test]
+    fn channel_mismatch() {
+        let (mut encoder, config) = stereo_encoder();
+
+        let frame = AudioFrame::new(SampleFormat::S16, 1, 960, 48000).unwrap();
+        assert!(encoder.send_frame(&config, None, SharedFrame::<AudioFrame<'static>>::new(frame)).is_err());
+        assert!(encoder.receive_packet(&config, None).is_err());
+
+        let frame = AudioFrame::new(SampleFormat::S16, 2, 960, 48000).unwrap();
+        encoder.send_frame(&config, None, SharedFrame::<AudioFrame<'static>>::new(frame)).unwrap();
+        assert!(encoder.receive_packet(&config, None).is_ok());
+    }
+
+    #[test]
+    fn fmtp() {
+        let params = AudioEncoderParameters {
+            audio: AudioParameters {
+                format: Some(SampleFormat::S16),
+                samples: None,
+                sample_rate: NonZeroU32::new(24000),
+                channel_layout: Some(ChannelLayout::STEREO),
+            },
+            encoder: EncoderParameters::default(),
+        };
+        let mut options = Variant::new_dict();
+        options.dict_set("application", "lowdelay".into());
+        options.dict_set("frame_duration", 2.5f32.into());
+        options.dict_set("fec", true.into());
+        let encoder = OpusEncoder::new(CodecID::OPUS, &params, Some(&options)).unwrap();
+
+        // Only the properties of the sent stream
+        assert_eq!(encoder.fmtp().to_string(), "sprop-maxcapturerate=24000;sprop-stereo=1;ptime=2;maxptime=3");
+    }
+
+    #[test]
+    fn frame_duration() {
+        assert_eq!(FrameDuration::try_from(2.5).unwrap(), FrameDuration::Ms2_5);
+        assert_eq!(FrameDuration::try_from(2.4999).unwrap(), FrameDuration::Ms2_5);
+        assert_eq!(FrameDuration::try_from(60.0).unwrap(), FrameDuration::Ms60);
+        assert!(FrameDuration::try_from(30.0).is_err());
+
+        assert_eq!(FrameDuration::Ms2_5.millis(), 2.5);
+        assert_eq!(FrameDuration::Ms20.samples(48000), 960);
+        assert_eq!(FrameDuration::Ms120.samples(8000), 960);
+    }
 }
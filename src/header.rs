@@ -0,0 +1,561 @@
+use media_core::{error::Error, invalid_param_error, Result};
+
+const OPUS_HEAD_MAGIC: &[u8; 8] = b"OpusHead";
+// Magic signature, version, channel count, pre-skip, input sample rate, output
+// gain and channel mapping family
+const OPUS_HEAD_SIZE: usize = 19;
+
+/// Identification header of an Opus stream, as defined in RFC 7845
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpusHead {
+    pub version: u8,
+    pub channels: u8,
+    /// Number of samples at 48kHz to discard from the decoder output when
+    /// starting playback
+    pub pre_skip: u16,
+    pub input_sample_rate: u32,
+    /// Gain to apply to the decoder output, in Q7.8 dB
+    pub output_gain: i16,
+    pub mapping_family: u8,
+    pub stream_count: u8,
+    pub coupled_count: u8,
+    pub mapping: Vec<u8>,
+    /// Demixing matrix of the mapping family 3, which replaces the mapping
+    /// table, as 16-bit little-endian coefficients
+    pub demixing_matrix: Vec<u8>,
+}
+
+impl OpusHead {
+    pub fn new(channels: u8, pre_skip: u16, input_sample_rate: u32) -> Self {
+        OpusHead {
+            version: 1,
+            channels,
+            pre_skip,
+            input_sample_rate,
+            output_gain: 0,
+            mapping_family: 0,
+            stream_count: 1,
+            coupled_count: channels.saturating_sub(1),
+            mapping: (0..channels).collect(),
+            demixing_matrix: Vec::new(),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < OPUS_HEAD_SIZE || &data[..8] != OPUS_HEAD_MAGIC {
+            return Err(Error::Invalid("OpusHead".into()));
+        }
+
+        let version = data[8];
+        // Only the major version 0 is compatible with this specification
+        if version >> 4 != 0 {
+            return Err(Error::Invalid("OpusHead version".into()));
+        }
+
+        let channels = data[9];
+        if channels == 0 {
+            return Err(Error::Invalid("OpusHead channel count".into()));
+        }
+
+        let pre_skip = u16::from_le_bytes([data[10], data[11]]);
+        let input_sample_rate = u32::from_le_bytes([data[12], data[13], data[14], data[15]]);
+        let output_gain = i16::from_le_bytes([data[16], data[17]]);
+        let mapping_family = data[18];
+
+        let (stream_count, coupled_count, mapping, demixing_matrix) = if mapping_family == 0 {
+            if channels > 2 {
+                return Err(Error::Invalid("OpusHead channel count".into()));
+            }
+
+            (1, channels - 1, (0..channels).collect(), Vec::new())
+        } else {
+            let counts = data.get(OPUS_HEAD_SIZE..OPUS_HEAD_SIZE + 2).ok_or_else(|| Error::Invalid("OpusHead mapping table".into()))?;
+            let stream_count = counts[0];
+            let coupled_count = counts[1];
+
+            if stream_count == 0 || coupled_count > stream_count || stream_count as u32 + coupled_count as u32 > 255 {
+                return Err(Error::Invalid("OpusHead stream count".into()));
+            }
+
+            let table = &data[OPUS_HEAD_SIZE + 2..];
+            if mapping_family == 3 {
+                // One 16-bit coefficient per output channel and decoded stream channel
+                let matrix_size = 2 * channels as usize * (stream_count + coupled_count) as usize;
+                let demixing_matrix = table.get(..matrix_size).ok_or_else(|| Error::Invalid("OpusHead demixing matrix".into()))?;
+
+                (stream_count, coupled_count, Vec::new(), demixing_matrix.to_vec())
+            } else {
+                let mapping = table.get(..channels as usize).ok_or_else(|| Error::Invalid("OpusHead mapping table".into()))?;
+
+                // Index 255 marks a silent channel
+                if mapping.iter().any(|&index| index != 255 && index >= stream_count + coupled_count) {
+                    return Err(Error::Invalid("OpusHead mapping table".into()));
+                }
+
+                (stream_count, coupled_count, mapping.to_vec(), Vec::new())
+            }
+        };
+
+        Ok(OpusHead {
+            version,
+            channels,
+            pre_skip,
+            input_sample_rate,
+            output_gain,
+            mapping_family,
+            stream_count,
+            coupled_count,
+            mapping,
+            demixing_matrix,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(OPUS_HEAD_SIZE + 2 + self.mapping.len() + self.demixing_matrix.len());
+
+        data.extend_from_slice(OPUS_HEAD_MAGIC);
+        data.push(self.version);
+        data.push(self.channels);
+        data.extend_from_slice(&self.pre_skip.to_le_bytes());
+        data.extend_from_slice(&self.input_sample_rate.to_le_bytes());
+        data.extend_from_slice(&self.output_gain.to_le_bytes());
+        data.push(self.mapping_family);
+
+        if self.mapping_family != 0 {
+            data.push(self.stream_count);
+            data.push(self.coupled_count);
+
+            if self.mapping_family == 3 {
+                data.extend_from_slice(&self.demixing_matrix);
+            } else {
+                data.extend_from_slice(&self.mapping);
+            }
+        }
+
+        data
+    }
+}
+
+const OPUS_TAGS_MAGIC: &[u8; 8] = b"OpusTags";
+
+const R128_TRACK_GAIN: &str = "R128_TRACK_GAIN";
+const R128_ALBUM_GAIN: &str = "R128_ALBUM_GAIN";
+const METADATA_BLOCK_PICTURE: &str = "METADATA_BLOCK_PICTURE";
+
+/// Comment header of an Opus stream, as defined in RFC 7845
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpusTags {
+    pub vendor: String,
+    /// User comments as `KEY=value` pairs
+    pub comments: Vec<(String, String)>,
+}
+
+impl OpusTags {
+    pub fn new(vendor: &str) -> Self {
+        OpusTags {
+            vendor: vendor.to_string(),
+            comments: Vec::new(),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 16 || &data[..8] != OPUS_TAGS_MAGIC {
+            return Err(Error::Invalid("OpusTags".into()));
+        }
+
+        let mut offset = 8;
+        let vendor = String::from_utf8_lossy(read_string(data, &mut offset)?).into_owned();
+        let count = read_u32(data, &mut offset)?;
+        let mut comments = Vec::new();
+
+        for _ in 0..count {
+            let comment = read_string(data, &mut offset)?;
+            // Comments without a separator are invalid, but are skipped rather than
+            // rejecting the whole header
+            if let Some(separator) = comment.iter().position(|&byte| byte == b'=') {
+                let key = String::from_utf8_lossy(&comment[..separator]).into_owned();
+                let value = String::from_utf8_lossy(&comment[separator + 1..]).into_owned();
+                comments.push((key, value));
+            }
+        }
+
+        Ok(OpusTags {
+            vendor,
+            comments,
+        })
+    }
+
+    /// Returns the value of the first comment with the key, which is case
+    /// insensitive
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.comments.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.comments.iter().filter(move |(name, _)| name.eq_ignore_ascii_case(key)).map(|(_, value)| value.as_str())
+    }
+
+    /// Adds a comment, whose key is made of the ASCII characters 0x20 to 0x7D
+    /// except '='
+    pub fn add(&mut self, key: &str, value: &str) -> Result<()> {
+        check_key(key)?;
+        self.push(key, value);
+
+        Ok(())
+    }
+
+    /// Replaces all the comments with the key by a single one
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        check_key(key)?;
+        self.replace(key, value);
+
+        Ok(())
+    }
+
+    // Adds a comment with a key known to be valid
+    fn push(&mut self, key: &str, value: &str) {
+        self.comments.push((key.to_string(), value.to_string()));
+    }
+
+    fn replace(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.push(key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.comments.retain(|(name, _)| !name.eq_ignore_ascii_case(key));
+    }
+
+    /// Track gain relative to the output gain of the OpusHead, in Q7.8 dB
+    pub fn track_gain(&self) -> Option<i16> {
+        self.get(R128_TRACK_GAIN).and_then(|value| value.trim().parse().ok())
+    }
+
+    /// Album gain relative to the output gain of the OpusHead, in Q7.8 dB
+    pub fn album_gain(&self) -> Option<i16> {
+        self.get(R128_ALBUM_GAIN).and_then(|value| value.trim().parse().ok())
+    }
+
+    pub fn set_track_gain(&mut self, gain: i16) {
+        self.replace(R128_TRACK_GAIN, &gain.to_string());
+    }
+
+    pub fn set_album_gain(&mut self, gain: i16) {
+        self.replace(R128_ALBUM_GAIN, &gain.to_string());
+    }
+
+    /// Decodes the embedded pictures, skipping the malformed ones
+    pub fn pictures(&self) -> Vec<Picture> {
+        self.get_all(METADATA_BLOCK_PICTURE).filter_map(|value| Picture::parse(&base64_decode(value)?).ok()).collect()
+    }
+
+    pub fn add_picture(&mut self, picture: &Picture) {
+        self.push(METADATA_BLOCK_PICTURE, &base64_encode(&picture.to_bytes()));
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        data.extend_from_slice(OPUS_TAGS_MAGIC);
+        data.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        data.extend_from_slice(self.vendor.as_bytes());
+        data.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+
+        for (key, value) in &self.comments {
+            data.extend_from_slice(&((key.len() + 1 + value.len()) as u32).to_le_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.push(b'=');
+            data.extend_from_slice(value.as_bytes());
+        }
+
+        data
+    }
+}
+
+/// Picture embedded in the comments as a FLAC picture block
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Picture {
+    /// Picture type as defined by the ID3v2 APIC frame, such as 3 for the
+    /// front cover
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel
+    pub depth: u32,
+    /// Number of colors of indexed pictures, or 0
+    pub colors: u32,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut offset = 0;
+        let read_u32 = |offset: &mut usize| -> Result<u32> {
+            let bytes = data.get(*offset..*offset + 4).ok_or_else(|| Error::Invalid("picture block".into()))?;
+            *offset += 4;
+
+            Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        let read_bytes = |offset: &mut usize| -> Result<&[u8]> {
+            let len = read_u32(offset)? as usize;
+            let bytes = data.get(*offset..).and_then(|data| data.get(..len)).ok_or_else(|| Error::Invalid("picture block".into()))?;
+            *offset += len;
+
+            Ok(bytes)
+        };
+
+        let picture_type = read_u32(&mut offset)?;
+        let mime_type = String::from_utf8_lossy(read_bytes(&mut offset)?).into_owned();
+        let description = String::from_utf8_lossy(read_bytes(&mut offset)?).into_owned();
+        let width = read_u32(&mut offset)?;
+        let height = read_u32(&mut offset)?;
+        let depth = read_u32(&mut offset)?;
+        let colors = read_u32(&mut offset)?;
+        let data = read_bytes(&mut offset)?.to_vec();
+
+        Ok(Picture {
+            picture_type,
+            mime_type,
+            description,
+            width,
+            height,
+            depth,
+            colors,
+            data,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(32 + self.mime_type.len() + self.description.len() + self.data.len());
+
+        data.extend_from_slice(&self.picture_type.to_be_bytes());
+        data.extend_from_slice(&(self.mime_type.len() as u32).to_be_bytes());
+        data.extend_from_slice(self.mime_type.as_bytes());
+        data.extend_from_slice(&(self.description.len() as u32).to_be_bytes());
+        data.extend_from_slice(self.description.as_bytes());
+        data.extend_from_slice(&self.width.to_be_bytes());
+        data.extend_from_slice(&self.height.to_be_bytes());
+        data.extend_from_slice(&self.depth.to_be_bytes());
+        data.extend_from_slice(&self.colors.to_be_bytes());
+        data.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.data);
+
+        data
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let value = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(value >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let mut value = 0u32;
+    let mut bits = 0;
+
+    for &byte in data {
+        let index = BASE64_ALPHABET.iter().position(|&c| c == byte)? as u32;
+        value = value << 6 | index;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((value >> bits) as u8);
+            value &= (1 << bits) - 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+// Field names of Vorbis comments
+fn check_key(key: &str) -> Result<()> {
+    if !key.bytes().all(|byte| (0x20..=0x7d).contains(&byte) && byte != b'=') {
+        return Err(invalid_param_error!(key));
+    }
+
+    Ok(())
+}
+
+fn read_u32(data: &[u8], offset: &mut usize) -> Result<u32> {
+    let bytes = data.get(*offset..*offset + 4).ok_or_else(|| Error::Invalid("OpusTags length".into()))?;
+    *offset += 4;
+
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_string<'a>(data: &'a [u8], offset: &mut usize) -> Result<&'a [u8]> {
+    let len = read_u32(data, offset)? as usize;
+    let string = data.get(*offset..).and_then(|data| data.get(..len)).ok_or_else(|| Error::Invalid("OpusTags length".into()))?;
+    *offset += len;
+
+    Ok(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opus_head_family_0() {
+        let head = OpusHead::new(2, 312, 44100);
+        let data = head.to_bytes();
+
+        assert_eq!(data.len(), OPUS_HEAD_SIZE);
+        assert_eq!(&data[..8], OPUS_HEAD_MAGIC);
+        assert_eq!(OpusHead::parse(&data).unwrap(), head);
+    }
+
+    #[test]
+    fn opus_head_family_1() {
+        let head = OpusHead {
+            output_gain: -256,
+            mapping_family: 1,
+            stream_count: 4,
+            coupled_count: 2,
+            mapping: vec![0, 4, 1, 2, 3, 5],
+            ..OpusHead::new(6, 312, 48000)
+        };
+        let data = head.to_bytes();
+
+        assert_eq!(data.len(), OPUS_HEAD_SIZE + 2 + 6);
+        assert_eq!(OpusHead::parse(&data).unwrap(), head);
+
+        // Mapping indices past the decoded channels are invalid, except 255
+        let mut data = data;
+        data[OPUS_HEAD_SIZE + 2] = 6;
+        assert!(OpusHead::parse(&data).is_err());
+        data[OPUS_HEAD_SIZE + 2] = 255;
+        assert!(OpusHead::parse(&data).is_ok());
+    }
+
+    #[test]
+    fn opus_head_family_3() {
+        // First order ambisonics in 2 coupled streams, with a 4x4 demixing matrix
+        let head = OpusHead {
+            mapping_family: 3,
+            stream_count: 2,
+            coupled_count: 2,
+            mapping: Vec::new(),
+            demixing_matrix: (0..32).collect(),
+            ..OpusHead::new(4, 312, 48000)
+        };
+        let data = head.to_bytes();
+
+        assert_eq!(data.len(), OPUS_HEAD_SIZE + 2 + 32);
+        assert_eq!(OpusHead::parse(&data).unwrap(), head);
+        assert!(OpusHead::parse(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn opus_head_invalid() {
+        let data = OpusHead::new(2, 312, 48000).to_bytes();
+
+        assert!(OpusHead::parse(&data[..OPUS_HEAD_SIZE - 1]).is_err());
+
+        let mut invalid = data.clone();
+        invalid[8] = 0x10;
+        assert!(OpusHead::parse(&invalid).is_err());
+
+        // The mapping family 0 is limited to stereo
+        let mut invalid = data;
+        invalid[9] = 3;
+        assert!(OpusHead::parse(&invalid).is_err());
+    }
+
+    #[test]
+    fn opus_tags() {
+        let mut tags = OpusTags::new("libopus 1.5.2");
+        tags.add("ARTIST", "first").unwrap();
+        tags.add("artist", "second").unwrap();
+        tags.set_track_gain(-512);
+        tags.set_album_gain(256);
+
+        let parsed = OpusTags::parse(&tags.to_bytes()).unwrap();
+        assert_eq!(parsed, tags);
+        assert_eq!(parsed.get("Artist"), Some("first"));
+        assert_eq!(parsed.get_all("ARTIST").collect::<Vec<_>>(), ["first", "second"]);
+        assert_eq!(parsed.track_gain(), Some(-512));
+        assert_eq!(parsed.album_gain(), Some(256));
+
+        tags.set("artist", "only").unwrap();
+        assert_eq!(tags.get_all("ARTIST").collect::<Vec<_>>(), ["only"]);
+        tags.remove("ARTIST");
+        assert_eq!(tags.get("ARTIST"), None);
+
+        // Keys cannot hold the separator or characters outside 0x20 to 0x7D
+        for key in ["KEY=", "KÉY", "KEY~", "KEY\n"] {
+            assert!(tags.add(key, "value").is_err());
+            assert!(tags.set(key, "value").is_err());
+        }
+        assert_eq!(tags.comments.len(), 2);
+    }
+
+    #[test]
+    fn opus_tags_invalid_comment() {
+        let mut tags = OpusTags::new("vendor");
+        tags.add("TITLE", "title").unwrap();
+        let mut data = tags.to_bytes();
+
+        // An extra comment without separator is skipped
+        data[18] += 1;
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.extend_from_slice(b"INVALID");
+        assert_eq!(OpusTags::parse(&data).unwrap(), tags);
+
+        assert!(OpusTags::parse(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn base64() {
+        for (data, encoded) in [(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"foob", "Zm9vYg=="), (b"fooba", "Zm9vYmE=")] {
+            assert_eq!(base64_encode(data), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), data);
+        }
+
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    fn pictures() {
+        let picture = Picture {
+            picture_type: 3,
+            mime_type: "image/png".to_string(),
+            description: "cover".to_string(),
+            width: 16,
+            height: 16,
+            depth: 24,
+            colors: 0,
+            data: (0..=255).collect(),
+        };
+        assert_eq!(Picture::parse(&picture.to_bytes()).unwrap(), picture);
+
+        let mut tags = OpusTags::new("vendor");
+        tags.add_picture(&picture);
+        // Malformed pictures are skipped
+        tags.add(METADATA_BLOCK_PICTURE, "AAAA").unwrap();
+
+        let parsed = OpusTags::parse(&tags.to_bytes()).unwrap();
+        assert_eq!(parsed.pictures(), [picture]);
+    }
+}
@@ -2,10 +2,82 @@
 pub mod decoder;
 #[cfg(feature = "encoder")]
 pub mod encoder;
+pub mod header;
+pub mod ogg;
+pub mod packet;
+pub mod repacketizer;
+pub mod rtp;
+pub mod sdp;
 
-use std::{borrow::Cow, ffi::CStr};
+use std::{borrow::Cow, ffi::CStr, str::FromStr};
 
 use media_codec_opus_sys as opus_sys;
+use media_core::error::Error;
+#[cfg(any(feature = "decoder", feature = "encoder"))]
+use media_core::{
+    audio::{Channel, ChannelLayout, ChannelMasks, ChannelOrder},
+    unsupported_error,
+};
+
+// Channels of the Vorbis channel order, used by the mapping family 1, for each
+// channel count
+#[cfg(any(feature = "decoder", feature = "encoder"))]
+const VORBIS_CHANNELS: [&[Channel]; 8] = {
+    use Channel::*;
+
+    [
+        &[FrontCenter],
+        &[FrontLeft, FrontRight],
+        &[FrontLeft, FrontCenter, FrontRight],
+        &[FrontLeft, FrontRight, BackLeft, BackRight],
+        &[FrontLeft, FrontCenter, FrontRight, BackLeft, BackRight],
+        &[FrontLeft, FrontCenter, FrontRight, BackLeft, BackRight, LowFrequency],
+        &[FrontLeft, FrontCenter, FrontRight, SideLeft, SideRight, BackCenter, LowFrequency],
+        &[FrontLeft, FrontCenter, FrontRight, SideLeft, SideRight, BackLeft, BackRight, LowFrequency],
+    ]
+};
+
+// Returns the index in the channel layout of each channel of the Vorbis channel
+// order. Layouts without channel positions are assumed to hold the Vorbis
+// channels in the default order, and side channels stand for the missing back
+// ones of the 4 to 6 channel layouts
+#[cfg(any(feature = "decoder", feature = "encoder"))]
+pub(crate) fn vorbis_channel_order(channel_layout: &ChannelLayout) -> media_core::Result<Vec<usize>> {
+    let channels = channel_layout.channels.get();
+    // A single channel needs no reordering, whatever its position
+    if channels == 1 {
+        return Ok(vec![0]);
+    }
+
+    let vorbis_channels = *VORBIS_CHANNELS.get(channels as usize - 1).ok_or_else(|| unsupported_error!(channels))?;
+
+    let default_layout;
+    let channel_layout = if channel_layout.order == ChannelOrder::Unspecified {
+        let mask = vorbis_channels.iter().fold(ChannelMasks::empty(), |mask, &channel| mask | ChannelMasks::from(channel));
+        default_layout = ChannelLayout::from_mask(mask)?;
+        &default_layout
+    } else {
+        channel_layout
+    };
+
+    let side_for_back = vorbis_channels.contains(&Channel::BackLeft) && !vorbis_channels.contains(&Channel::SideLeft);
+
+    vorbis_channels
+        .iter()
+        .map(|&channel| {
+            let fallback = match channel {
+                Channel::BackLeft if side_for_back => Some(Channel::SideLeft),
+                Channel::BackRight if side_for_back => Some(Channel::SideRight),
+                _ => None,
+            };
+
+            channel_layout
+                .get_index_from_channel(channel)
+                .or_else(|| fallback.and_then(|channel| channel_layout.get_index_from_channel(channel)))
+                .ok_or_else(|| Error::Unsupported(format!("channel layout without {:?} for the Vorbis channel order", channel).into()))
+        })
+        .collect()
+}
 
 pub(crate) fn opus_error_string(error: i32) -> Cow<'static, str> {
     unsafe { CStr::from_ptr(opus_sys::opus_strerror(error)).to_string_lossy() }
@@ -18,3 +90,138 @@ pub enum Application {
     Audio    = opus_sys::OPUS_APPLICATION_AUDIO,
     LowDelay = opus_sys::OPUS_APPLICATION_RESTRICTED_LOWDELAY,
 }
+
+impl Application {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Application::VoIP => "voip",
+            Application::Audio => "audio",
+            Application::LowDelay => "lowdelay",
+        }
+    }
+}
+
+impl FromStr for Application {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "voip" => Ok(Application::VoIP),
+            "audio" => Ok(Application::Audio),
+            "lowdelay" => Ok(Application::LowDelay),
+            _ => Err(Error::Invalid(format!("application {}", s).into())),
+        }
+    }
+}
+
+impl TryFrom<i32> for Application {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            opus_sys::OPUS_APPLICATION_VOIP => Ok(Application::VoIP),
+            opus_sys::OPUS_APPLICATION_AUDIO => Ok(Application::Audio),
+            opus_sys::OPUS_APPLICATION_RESTRICTED_LOWDELAY => Ok(Application::LowDelay),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Type of the signal being encoded, which biases the mode decisions
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Signal {
+    #[default]
+    Auto  = opus_sys::OPUS_AUTO,
+    Voice = opus_sys::OPUS_SIGNAL_VOICE,
+    Music = opus_sys::OPUS_SIGNAL_MUSIC,
+}
+
+impl Signal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Signal::Auto => "auto",
+            Signal::Voice => "voice",
+            Signal::Music => "music",
+        }
+    }
+}
+
+impl FromStr for Signal {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Signal::Auto),
+            "voice" => Ok(Signal::Voice),
+            "music" => Ok(Signal::Music),
+            _ => Err(Error::Invalid(format!("signal {}", s).into())),
+        }
+    }
+}
+
+impl TryFrom<i32> for Signal {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            opus_sys::OPUS_AUTO => Ok(Signal::Auto),
+            opus_sys::OPUS_SIGNAL_VOICE => Ok(Signal::Voice),
+            opus_sys::OPUS_SIGNAL_MUSIC => Ok(Signal::Music),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Audio bandwidth of an Opus stream
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bandwidth {
+    Narrowband    = opus_sys::OPUS_BANDWIDTH_NARROWBAND,
+    Mediumband    = opus_sys::OPUS_BANDWIDTH_MEDIUMBAND,
+    Wideband      = opus_sys::OPUS_BANDWIDTH_WIDEBAND,
+    SuperWideband = opus_sys::OPUS_BANDWIDTH_SUPERWIDEBAND,
+    Fullband      = opus_sys::OPUS_BANDWIDTH_FULLBAND,
+}
+
+impl Bandwidth {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Bandwidth::Narrowband => "narrowband",
+            Bandwidth::Mediumband => "mediumband",
+            Bandwidth::Wideband => "wideband",
+            Bandwidth::SuperWideband => "superwideband",
+            Bandwidth::Fullband => "fullband",
+        }
+    }
+}
+
+impl FromStr for Bandwidth {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "narrowband" => Ok(Bandwidth::Narrowband),
+            "mediumband" => Ok(Bandwidth::Mediumband),
+            "wideband" => Ok(Bandwidth::Wideband),
+            "superwideband" => Ok(Bandwidth::SuperWideband),
+            "fullband" => Ok(Bandwidth::Fullband),
+            _ => Err(Error::Invalid(format!("bandwidth {}", s).into())),
+        }
+    }
+}
+
+impl TryFrom<i32> for Bandwidth {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            opus_sys::OPUS_BANDWIDTH_NARROWBAND => Ok(Bandwidth::Narrowband),
+            opus_sys::OPUS_BANDWIDTH_MEDIUMBAND => Ok(Bandwidth::Mediumband),
+            opus_sys::OPUS_BANDWIDTH_WIDEBAND => Ok(Bandwidth::Wideband),
+            opus_sys::OPUS_BANDWIDTH_SUPERWIDEBAND => Ok(Bandwidth::SuperWideband),
+            opus_sys::OPUS_BANDWIDTH_FULLBAND => Ok(Bandwidth::Fullband),
+            _ => Err(value),
+        }
+    }
+}
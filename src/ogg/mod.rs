@@ -0,0 +1,49 @@
+mod reader;
+mod writer;
+
+pub use reader::OggOpusReader;
+pub use writer::OggOpusWriter;
+
+const OGG_PAGE_MAGIC: &[u8; 4] = b"OggS";
+// Capture pattern, version, header type, granule position, serial number,
+// sequence number, checksum and segment count
+const OGG_PAGE_HEADER_SIZE: usize = 27;
+const OGG_MAX_SEGMENTS: usize = 255;
+
+const OGG_FLAG_CONTINUED: u8 = 0x01;
+const OGG_FLAG_BOS: u8 = 0x02;
+const OGG_FLAG_EOS: u8 = 0x04;
+
+// Opus timestamps in Ogg are always at 48kHz
+const OGG_OPUS_SAMPLE_RATE: u32 = 48000;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04c11db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+// Ogg uses the unreflected CRC-32 with a zero initial value and no final XOR
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize])
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    mem,
+};
+
+use media_codec::packet::Packet;
+use media_core::{error::Error, rational::Rational64, Result};
+
+use super::{crc32, OGG_FLAG_BOS, OGG_FLAG_CONTINUED, OGG_FLAG_EOS, OGG_OPUS_SAMPLE_RATE, OGG_PAGE_HEADER_SIZE, OGG_PAGE_MAGIC};
+use crate::{
+    header::{OpusHead, OpusTags},
+    packet::packet_samples,
+};
+
+// Samples at 48kHz decoded before the seek target for the decoder to converge
+const OGG_OPUS_PREROLL: i64 = 3840;
+// Bisection stops once the range is small enough to scan page by page
+const OGG_SEEK_LINEAR_THRESHOLD: u64 = 64 * 1024;
+const OGG_SEEK_BUFFER_SIZE: usize = 8 * 1024;
+
+struct Page {
+    flags: u8,
+    granule_position: i64,
+    serial: u32,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Page {
+    // Splits the page into packet pieces, along with whether each piece ends a
+    // packet
+    fn packets(&self) -> Vec<(&[u8], bool)> {
+        let mut packets = Vec::new();
+        let mut start = 0;
+        let mut end = 0;
+
+        for &size in &self.segments {
+            end += size as usize;
+
+            if size < 255 {
+                packets.push((&self.data[start..end], true));
+                start = end;
+            }
+        }
+
+        if start < end || self.segments.last() == Some(&255) {
+            packets.push((&self.data[start..end], false));
+        }
+
+        packets
+    }
+
+    // Drops the end of a packet started on a previous page, and returns whether
+    // it ends on this page
+    fn skip_continued(&mut self) -> bool {
+        let end = self.segments.iter().position(|&size| size < 255);
+        let count = end.map_or(self.segments.len(), |index| index + 1);
+        let size: usize = self.segments[..count].iter().map(|&size| size as usize).sum();
+
+        self.segments.drain(..count);
+        self.data.drain(..size);
+        self.flags &= !OGG_FLAG_CONTINUED;
+
+        end.is_some()
+    }
+}
+
+/// Ogg Opus stream reader, as defined in RFC 7845
+pub struct OggOpusReader<R: Read> {
+    reader: R,
+    serial: u32,
+    head: OpusHead,
+    tags: OpusTags,
+    // Data of a packet continued on the next page
+    partial: Vec<u8>,
+    // Granule position at the end of the last page
+    granule_position: i64,
+    // Packets of the last read page with their start position and duration
+    packets: VecDeque<(Vec<u8>, i64, i64)>,
+    end_trim: u32,
+    eos: bool,
+    // Whether the next page may continue a packet whose start was skipped
+    resync: bool,
+}
+
+impl<R: Read> OggOpusReader<R> {
+    /// Creates a reader and reads the OpusHead and OpusTags headers of the
+    /// first Opus stream
+    pub fn new(mut reader: R) -> Result<Self> {
+        // Skip the streams multiplexed with the Opus stream until its first page
+        let (serial, head) = loop {
+            let page = read_page(&mut reader)?.ok_or_else(|| Error::Invalid("no Opus stream".into()))?;
+            if page.flags & OGG_FLAG_BOS == 0 {
+                return Err(Error::Invalid("no Opus stream".into()));
+            }
+
+            if let Some((data, true)) = page.packets().first() {
+                if data.starts_with(b"OpusHead") {
+                    break (page.serial, OpusHead::parse(data)?);
+                }
+            }
+        };
+
+        let mut ogg_reader = OggOpusReader {
+            reader,
+            serial,
+            head,
+            tags: OpusTags::default(),
+            partial: Vec::new(),
+            granule_position: 0,
+            packets: VecDeque::new(),
+            end_trim: 0,
+            eos: false,
+            resync: false,
+        };
+
+        // The comment header may span several pages, and the audio data starts on
+        // the page following its end
+        let tags = loop {
+            let page = ogg_reader.read_stream_page()?.ok_or_else(|| Error::Invalid("OpusTags".into()))?;
+            let packets = page.packets();
+            ogg_reader.partial.extend_from_slice(packets.first().map_or(&[][..], |(data, _)| data));
+
+            if packets.first().is_some_and(|&(_, complete)| complete) {
+                break mem::take(&mut ogg_reader.partial);
+            }
+        };
+        ogg_reader.tags = OpusTags::parse(&tags)?;
+
+        Ok(ogg_reader)
+    }
+
+    pub fn head(&self) -> &OpusHead {
+        &self.head
+    }
+
+    pub fn tags(&self) -> &OpusTags {
+        &self.tags
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Number of samples at 48kHz the decoder discards at the start of the
+    /// stream
+    pub fn pre_skip(&self) -> u32 {
+        self.head.pre_skip as u32
+    }
+
+    /// Number of samples at 48kHz trimmed from the end of the last packet,
+    /// known once the last page is read
+    pub fn end_trim(&self) -> u32 {
+        self.end_trim
+    }
+
+    /// Reads the next packet, timestamped at 48kHz so that the first sample
+    /// after the pre-skip is at zero, or returns `None` at the end of the
+    /// stream
+    pub fn read_packet(&mut self) -> Result<Option<Packet<'static>>> {
+        while self.packets.is_empty() {
+            if self.eos || !self.read_packets()? {
+                return Ok(None);
+            }
+        }
+
+        let Some((data, position, duration)) = self.packets.pop_front() else {
+            return Ok(None);
+        };
+
+        let mut packet = Packet::new(data.len());
+        packet.data_mut().ok_or_else(|| Error::Invalid("packet not writable".into()))?.copy_from_slice(&data);
+        packet.pts = Some(position - self.head.pre_skip as i64);
+        packet.duration = Some(duration);
+        packet.time_base = Some(Rational64::new(1, OGG_OPUS_SAMPLE_RATE as i64));
+
+        Ok(Some(packet))
+    }
+
+    // Reads the next page of the stream and queues its completed packets
+    fn read_packets(&mut self) -> Result<bool> {
+        let Some(page) = self.read_stream_page()? else {
+            return Ok(false);
+        };
+
+        let mut packets = Vec::new();
+        for (data, complete) in page.packets() {
+            self.partial.extend_from_slice(data);
+
+            if complete {
+                let data = mem::take(&mut self.partial);
+                let samples = packet_samples(&data, OGG_OPUS_SAMPLE_RATE)? as i64;
+                packets.push((data, samples));
+            }
+        }
+
+        // Pages where no packet ends have no granule position
+        if packets.is_empty() {
+            if page.granule_position >= 0 {
+                self.granule_position = page.granule_position;
+            }
+
+            return Ok(true);
+        }
+
+        let total: i64 = packets.iter().map(|(_, samples)| samples).sum();
+        let end = page.granule_position;
+        self.eos = page.flags & OGG_FLAG_EOS != 0;
+
+        // The last page may end before its packets do, which trims the stream, any
+        // other page is aligned on its granule position
+        let mut position = if self.eos && end < self.granule_position + total {
+            self.granule_position
+        } else {
+            end - total
+        };
+
+        for (data, samples) in packets {
+            let duration = samples.min(end - position).max(0);
+            self.end_trim = (samples - duration) as u32;
+            self.packets.push_back((data, position, duration));
+            position += samples;
+        }
+
+        self.granule_position = end;
+
+        Ok(true)
+    }
+
+    // Reads the next page of the Opus stream, skipping the pages of the other
+    // multiplexed streams
+    fn read_stream_page(&mut self) -> Result<Option<Page>> {
+        loop {
+            let Some(mut page) = read_page(&mut self.reader)? else {
+                return Ok(None);
+            };
+
+            if page.serial != self.serial {
+                continue;
+            }
+
+            if self.resync && page.flags & OGG_FLAG_CONTINUED != 0 && !page.skip_continued() {
+                continue;
+            }
+            self.resync = false;
+
+            // A packet left unfinished by the previous page is dropped unless this page
+            // continues it
+            if page.flags & OGG_FLAG_CONTINUED == 0 {
+                self.partial.clear();
+            }
+
+            return Ok(Some(page));
+        }
+    }
+}
+
+impl<R: Read + Seek> OggOpusReader<R> {
+    /// Seeks so that the next packets start at least 80ms before the sample at
+    /// 48kHz `position`, counted from the end of the pre-skip, for the decoder
+    /// to converge. Returns the number of samples at 48kHz to discard from
+    /// the decoder output for it to start exactly at `position`, which is
+    /// passed to the `pre_skip` option of a flushed `OpusDecoder`
+    pub fn seek(&mut self, position: u64) -> Result<u32> {
+        let target = position as i64 + self.head.pre_skip as i64;
+        let preroll_target = (target - OGG_OPUS_PREROLL).max(0);
+
+        // Find the last page ending at or before the pre-roll start, as the
+        // packets of the following page start from its granule position. A packet
+        // continued on the following page is dropped when resyncing, so the search
+        // steps back to the page where it starts
+        let mut page_target = preroll_target;
+        let (offset, granule_position) = loop {
+            let (offset, granule_position) = self.find_preroll_page(page_target)?;
+            if granule_position <= 0 || !self.continues_packet(offset)? {
+                break (offset, granule_position);
+            }
+
+            page_target = granule_position - 1;
+        };
+
+        self.reader.seek(SeekFrom::Start(offset)).map_err(|err| Error::Failed(err.to_string().into()))?;
+        self.partial.clear();
+        self.packets.clear();
+        self.granule_position = granule_position;
+        self.end_trim = 0;
+        self.eos = false;
+        self.resync = true;
+
+        while self.packets.is_empty() && !self.eos {
+            if !self.read_packets()? {
+                break;
+            }
+        }
+
+        // Keep only the last packet starting before the pre-roll start
+        while self.packets.get(1).is_some_and(|&(_, start, _)| start <= preroll_target) {
+            self.packets.pop_front();
+        }
+
+        let start = self.packets.front().map_or(self.granule_position, |&(_, start, _)| start);
+
+        Ok((target - start).max(0) as u32)
+    }
+
+    // Bisects the stream for the last page of the Opus stream with a granule
+    // position at or before the target, and returns the offset following it
+    fn find_preroll_page(&mut self, target: i64) -> Result<(u64, i64)> {
+        let mut low = 0;
+        let mut high = self.reader.seek(SeekFrom::End(0)).map_err(|err| Error::Failed(err.to_string().into()))?;
+        // The header pages are at granule position 0, so the start of the stream is
+        // always a candidate
+        let mut best = (0, 0);
+
+        while high - low > OGG_SEEK_LINEAR_THRESHOLD {
+            let middle = low + (high - low) / 2;
+
+            match self.find_granule_page(middle, high)? {
+                Some((end, granule_position)) if granule_position <= target => {
+                    best = (end, granule_position);
+                    low = end;
+                }
+                _ => high = middle,
+            }
+        }
+
+        // Scan the remaining range page by page
+        let mut offset = low;
+        while let Some((end, granule_position)) = self.find_granule_page(offset, u64::MAX)? {
+            if granule_position > target {
+                break;
+            }
+
+            best = (end, granule_position);
+            offset = end;
+        }
+
+        Ok(best)
+    }
+
+    // Returns whether the first page of the Opus stream at or after `offset`
+    // continues a packet
+    fn continues_packet(&mut self, mut offset: u64) -> Result<bool> {
+        while let Some((_, end, page)) = self.find_page(offset)? {
+            if page.serial == self.serial {
+                return Ok(page.flags & OGG_FLAG_CONTINUED != 0);
+            }
+
+            offset = end;
+        }
+
+        Ok(false)
+    }
+
+    // Finds the first page of the Opus stream with a granule position starting
+    // in `offset..limit`, and returns the offset following it with its granule
+    // position
+    fn find_granule_page(&mut self, mut offset: u64, limit: u64) -> Result<Option<(u64, i64)>> {
+        while let Some((start, end, page)) = self.find_page(offset)? {
+            if start >= limit {
+                break;
+            }
+
+            if page.serial == self.serial && page.granule_position >= 0 {
+                return Ok(Some((end, page.granule_position)));
+            }
+
+            offset = end;
+        }
+
+        Ok(None)
+    }
+
+    // Finds the first valid page starting at or after `offset`, and returns its
+    // start and end offsets
+    fn find_page(&mut self, offset: u64) -> Result<Option<(u64, u64, Page)>> {
+        let mut buffer = vec![0u8; OGG_SEEK_BUFFER_SIZE];
+        let mut offset = offset;
+
+        loop {
+            self.reader.seek(SeekFrom::Start(offset)).map_err(|err| Error::Failed(err.to_string().into()))?;
+            let size = read_full(&mut self.reader, &mut buffer)?;
+            if size < OGG_PAGE_MAGIC.len() {
+                return Ok(None);
+            }
+
+            // Any capture pattern may be a false positive, which fails the checksum
+            for index in 0..=size - OGG_PAGE_MAGIC.len() {
+                if &buffer[index..index + OGG_PAGE_MAGIC.len()] != OGG_PAGE_MAGIC {
+                    continue;
+                }
+
+                let start = offset + index as u64;
+                self.reader.seek(SeekFrom::Start(start)).map_err(|err| Error::Failed(err.to_string().into()))?;
+
+                if let Ok(Some(page)) = read_page(&mut self.reader) {
+                    let end = self.reader.stream_position().map_err(|err| Error::Failed(err.to_string().into()))?;
+                    return Ok(Some((start, end, page)));
+                }
+            }
+
+            if size < buffer.len() {
+                return Ok(None);
+            }
+
+            offset += (size - OGG_PAGE_MAGIC.len() + 1) as u64;
+        }
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut size = 0;
+
+    while size < buffer.len() {
+        match reader.read(&mut buffer[size..]) {
+            Ok(0) => break,
+            Ok(read) => size += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(Error::Failed(err.to_string().into())),
+        }
+    }
+
+    Ok(size)
+}
+
+fn read_page<R: Read>(reader: &mut R) -> Result<Option<Page>> {
+    let mut header = [0u8; OGG_PAGE_HEADER_SIZE];
+
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(Error::Failed(err.to_string().into())),
+    }
+
+    if &header[..4] != OGG_PAGE_MAGIC || header[4] != 0 {
+        return Err(Error::Invalid("Ogg page".into()));
+    }
+
+    let mut segments = vec![0u8; header[26] as usize];
+    reader.read_exact(&mut segments).map_err(|err| Error::Failed(err.to_string().into()))?;
+
+    let size = segments.iter().map(|&size| size as usize).sum();
+    let mut data = vec![0u8; size];
+    reader.read_exact(&mut data).map_err(|err| Error::Failed(err.to_string().into()))?;
+
+    let checksum = u32::from_le_bytes([header[22], header[23], header[24], header[25]]);
+    header[22..26].fill(0);
+
+    let mut page = Vec::with_capacity(OGG_PAGE_HEADER_SIZE + segments.len() + data.len());
+    page.extend_from_slice(&header);
+    page.extend_from_slice(&segments);
+    page.extend_from_slice(&data);
+
+    if crc32(&page) != checksum {
+        return Err(Error::Invalid("Ogg page checksum".into()));
+    }
+
+    Ok(Some(Page {
+        flags: header[5],
+        granule_position: i64::from_le_bytes(header[6..14].try_into().unwrap()),
+        serial: u32::from_le_bytes([header[14], header[15], header[16], header[17]]),
+        segments,
+        data,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::*;
+    use crate::ogg::OggOpusWriter;
+
+    // 20ms CELT fullband frame
+    const TOC: u8 = 0xf8;
+
+    #[test]
+    fn writer_round_trip() {
+        let mut tags = OpusTags::new("test");
+        tags.add("TITLE", "round trip").unwrap();
+
+        let mut writer = OggOpusWriter::new(Vec::new(), &OpusHead::new(2, 312, 44100), &tags, 0x1234).unwrap();
+        writer.set_max_page_duration(Duration::from_millis(40));
+
+        // The second packet spans several pages
+        let mut packets = vec![vec![TOC, 1, 2, 3], vec![TOC; 70000]];
+        packets.extend((0..4).map(|index| vec![TOC, index]));
+        for packet in &packets {
+            writer.write_data(packet).unwrap();
+        }
+        assert_eq!(writer.granule_position(), 5 * 960);
+
+        let data = writer.finish(500).unwrap();
+        let mut reader = OggOpusReader::new(Cursor::new(data)).unwrap();
+        assert_eq!(reader.serial(), 0x1234);
+        assert_eq!(reader.pre_skip(), 312);
+        assert_eq!(reader.head().input_sample_rate, 44100);
+        assert_eq!(reader.tags().get("TITLE"), Some("round trip"));
+
+        for (index, expected) in packets.iter().enumerate() {
+            let packet = reader.read_packet().unwrap().unwrap();
+            assert_eq!(packet.data(), expected.as_slice());
+            assert_eq!(packet.pts, Some(index as i64 * 960 - 312));
+
+            let duration = if index == packets.len() - 1 {
+                960 - 500
+            } else {
+                960
+            };
+            assert_eq!(packet.duration, Some(duration));
+        }
+
+        assert!(reader.read_packet().unwrap().is_none());
+        assert_eq!(reader.end_trim(), 500);
+    }
+
+    #[test]
+    fn seek() {
+        let head = OpusHead::new(2, 312, 48000);
+        let mut writer = OggOpusWriter::new(Vec::new(), &head, &OpusTags::new("test"), 1).unwrap();
+        writer.set_max_page_duration(Duration::from_millis(100));
+
+        // Packets hold their index, and some of them span several pages
+        let count = 50;
+        for index in 0..count {
+            let mut packet = vec![TOC, index as u8];
+            if index % 15 == 10 {
+                packet.resize(300 * 255, 0);
+            }
+            writer.write_data(&packet).unwrap();
+        }
+
+        let data = writer.finish(0).unwrap();
+        let mut reader = OggOpusReader::new(Cursor::new(data)).unwrap();
+
+        for position in (0..count * 960).step_by(240) {
+            let discard = reader.seek(position as u64).unwrap();
+
+            let packet = reader.read_packet().unwrap().unwrap();
+            let index = packet.data()[1] as i64;
+            assert_eq!(packet.pts, Some(index * 960 - 312));
+            assert_eq!(discard as i64, position as i64 - packet.pts.unwrap());
+
+            // The pre-roll lasts at least 80ms, or up to the start of the stream
+            assert!(discard >= 3840 || index == 0, "position {} discard {}", position, discard);
+
+            for next in index + 1..(index + 4).min(count as i64) {
+                let packet = reader.read_packet().unwrap().unwrap();
+                assert_eq!(packet.data()[1] as i64, next);
+                assert_eq!(packet.pts, Some(next * 960 - 312));
+            }
+        }
+    }
+}
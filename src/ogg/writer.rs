@@ -0,0 +1,263 @@
+use std::{io::Write, time::Duration};
+
+use media_codec::packet::Packet;
+use media_core::{error::Error, Result};
+
+use super::{crc32, OGG_FLAG_BOS, OGG_FLAG_CONTINUED, OGG_FLAG_EOS, OGG_MAX_SEGMENTS, OGG_OPUS_SAMPLE_RATE, OGG_PAGE_HEADER_SIZE, OGG_PAGE_MAGIC};
+use crate::{
+    header::{OpusHead, OpusTags},
+    packet::packet_samples,
+};
+
+// Pages are flushed once they hold 1s of audio by default
+const DEFAULT_MAX_PAGE_DURATION: u64 = OGG_OPUS_SAMPLE_RATE as u64;
+
+/// Ogg Opus stream writer, as defined in RFC 7845
+pub struct OggOpusWriter<W: Write> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+    // Granule position at the end of the last written packet
+    granule_position: u64,
+    // Lacing values and data of the page being built
+    segments: Vec<u8>,
+    data: Vec<u8>,
+    flags: u8,
+    // Granule position of the last packet completed on the page being built
+    page_granule_position: Option<u64>,
+    page_start: u64,
+    max_page_duration: u64,
+    // The last packet is held back so that it can be written on the last page
+    last_packet: Option<Vec<u8>>,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    /// Creates a writer and writes the OpusHead and OpusTags header pages
+    pub fn new(writer: W, head: &OpusHead, tags: &OpusTags, serial: u32) -> Result<Self> {
+        let mut ogg_writer = OggOpusWriter {
+            writer,
+            serial,
+            sequence: 0,
+            granule_position: 0,
+            segments: Vec::with_capacity(OGG_MAX_SEGMENTS),
+            data: Vec::new(),
+            flags: OGG_FLAG_BOS,
+            page_granule_position: None,
+            page_start: 0,
+            max_page_duration: DEFAULT_MAX_PAGE_DURATION,
+            last_packet: None,
+        };
+
+        // Each header packet must be alone on its page, and the audio data starts
+        // on a new page
+        ogg_writer.append_packet(&head.to_bytes(), 0)?;
+        ogg_writer.write_page()?;
+        ogg_writer.append_packet(&tags.to_bytes(), 0)?;
+        ogg_writer.write_page()?;
+
+        Ok(ogg_writer)
+    }
+
+    /// Sets the maximum duration of audio held by a page before it is written,
+    /// which trades overhead for latency
+    pub fn set_max_page_duration(&mut self, duration: Duration) {
+        self.max_page_duration = (duration.as_micros() as u64 * OGG_OPUS_SAMPLE_RATE as u64 / 1_000_000).max(1);
+    }
+
+    /// Granule position at the end of the packets written so far, including the
+    /// pre-skip
+    pub fn granule_position(&self) -> u64 {
+        self.granule_position
+    }
+
+    pub fn write_packet(&mut self, packet: &Packet) -> Result<()> {
+        self.write_data(packet.data())
+    }
+
+    pub fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        packet_samples(data, OGG_OPUS_SAMPLE_RATE)?;
+
+        if let Some(last_packet) = self.last_packet.replace(data.to_vec()) {
+            self.push_audio_packet(&last_packet)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the pending page, except for the held back last packet, and
+    /// flushes the underlying writer
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.segments.is_empty() {
+            self.write_page()?;
+        }
+
+        self.writer.flush().map_err(|err| Error::Failed(err.to_string().into()))
+    }
+
+    /// Writes the last page, trimming `end_padding` samples at 48kHz from the
+    /// end of the stream, and returns the underlying writer
+    pub fn finish(mut self, end_padding: u32) -> Result<W> {
+        if let Some(last_packet) = self.last_packet.take() {
+            self.push_audio_packet(&last_packet)?;
+        }
+
+        // The granule position of the last page marks the end of the decoded audio
+        let granule_position = self.granule_position.saturating_sub(end_padding as u64).max(self.page_start);
+        if self.page_granule_position.is_some() {
+            self.page_granule_position = Some(granule_position);
+        }
+
+        self.flags |= OGG_FLAG_EOS;
+        self.write_page()?;
+        self.writer.flush().map_err(|err| Error::Failed(err.to_string().into()))?;
+
+        Ok(self.writer)
+    }
+
+    fn push_audio_packet(&mut self, data: &[u8]) -> Result<()> {
+        if !self.segments.is_empty() && self.granule_position - self.page_start >= self.max_page_duration {
+            self.write_page()?;
+        }
+
+        self.granule_position += packet_samples(data, OGG_OPUS_SAMPLE_RATE)? as u64;
+        self.append_packet(data, self.granule_position)
+    }
+
+    fn append_packet(&mut self, data: &[u8], granule_position: u64) -> Result<()> {
+        // A packet is laced into 255-byte segments and a final shorter segment
+        let mut lacing_values = data.len() / 255 + 1;
+        let mut offset = 0;
+
+        loop {
+            let available = OGG_MAX_SEGMENTS - self.segments.len();
+            if available == 0 {
+                self.write_page()?;
+                // A packet that starts on the new page does not continue one
+                if offset > 0 {
+                    self.flags |= OGG_FLAG_CONTINUED;
+                }
+                continue;
+            }
+
+            let count = available.min(lacing_values);
+            for _ in 0..count {
+                let size = (data.len() - offset).min(255);
+                self.segments.push(size as u8);
+                self.data.extend_from_slice(&data[offset..offset + size]);
+                offset += size;
+            }
+
+            lacing_values -= count;
+            if lacing_values == 0 {
+                break;
+            }
+        }
+
+        self.page_granule_position = Some(granule_position);
+
+        Ok(())
+    }
+
+    fn write_page(&mut self) -> Result<()> {
+        let mut page = Vec::with_capacity(OGG_PAGE_HEADER_SIZE + self.segments.len() + self.data.len());
+        // Pages where no packet ends have no granule position
+        let granule_position = self.page_granule_position.map_or(-1, |granule_position| granule_position as i64);
+
+        page.extend_from_slice(OGG_PAGE_MAGIC);
+        page.push(0);
+        page.push(self.flags);
+        page.extend_from_slice(&granule_position.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(self.segments.len() as u8);
+        page.extend_from_slice(&self.segments);
+        page.extend_from_slice(&self.data);
+
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.writer.write_all(&page).map_err(|err| Error::Failed(err.to_string().into()))?;
+
+        self.sequence += 1;
+        self.segments.clear();
+        self.data.clear();
+        self.flags = 0;
+        self.page_granule_position = None;
+        self.page_start = self.granule_position;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ogg::crc32;
+
+    // Returns the header type, granule position and lacing values of each page
+    fn parse_pages(mut data: &[u8]) -> Vec<(u8, i64, Vec<u8>)> {
+        let mut pages = Vec::new();
+
+        while !data.is_empty() {
+            let segment_count = data[26] as usize;
+            let segments = data[OGG_PAGE_HEADER_SIZE..OGG_PAGE_HEADER_SIZE + segment_count].to_vec();
+            let size = OGG_PAGE_HEADER_SIZE + segment_count + segments.iter().map(|&size| size as usize).sum::<usize>();
+
+            let mut page = data[..size].to_vec();
+            let checksum = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].fill(0);
+            assert_eq!(crc32(&page), checksum);
+
+            pages.push((data[5], i64::from_le_bytes(data[6..14].try_into().unwrap()), segments));
+            data = &data[size..];
+        }
+
+        pages
+    }
+
+    fn new_writer() -> OggOpusWriter<Vec<u8>> {
+        OggOpusWriter::new(Vec::new(), &OpusHead::new(2, 312, 48000), &OpusTags::new("test"), 1).unwrap()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0x89a1897f);
+    }
+
+    #[test]
+    fn packet_ending_at_page_boundary() {
+        let mut writer = new_writer();
+
+        // 254 full segments and a terminating empty one fill the page exactly
+        writer.append_packet(&[0; 254 * 255], 960).unwrap();
+        writer.append_packet(&[0; 10], 1920).unwrap();
+        writer.write_page().unwrap();
+
+        let pages = parse_pages(&writer.writer);
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[2].0, 0);
+        assert_eq!(pages[2].1, 960);
+        assert_eq!(pages[2].2.len(), OGG_MAX_SEGMENTS);
+        assert_eq!(pages[3].0, 0);
+        assert_eq!(pages[3].1, 1920);
+        assert_eq!(pages[3].2, [10]);
+    }
+
+    #[test]
+    fn packet_spanning_pages() {
+        let mut writer = new_writer();
+
+        writer.append_packet(&[0; 255 * 255], 960).unwrap();
+        writer.write_page().unwrap();
+
+        let pages = parse_pages(&writer.writer);
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[2].0, 0);
+        assert_eq!(pages[2].1, -1);
+        assert_eq!(pages[2].2, [255; OGG_MAX_SEGMENTS]);
+        assert_eq!(pages[3].0, OGG_FLAG_CONTINUED);
+        assert_eq!(pages[3].1, 960);
+        assert_eq!(pages[3].2, [0]);
+    }
+}
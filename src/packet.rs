@@ -0,0 +1,347 @@
+use std::{os::raw::c_int, ptr, time::Duration};
+
+use media_codec::packet::Packet;
+use media_core::{error::Error, Result};
+
+use crate::{opus_error_string, opus_sys, Bandwidth};
+
+// A packet holds up to 120ms of audio, that is 48 frames of 2.5ms
+const MAX_FRAMES: usize = 48;
+
+/// Coding mode of an Opus packet, as signaled by its TOC byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Silk,
+    Hybrid,
+    Celt,
+}
+
+/// Opus packet parsed without decoding it, as defined in RFC 6716 section 3
+#[derive(Clone, Debug)]
+pub struct OpusPacket<'a> {
+    data: &'a [u8],
+    toc: u8,
+    frames: Vec<&'a [u8]>,
+    padding: usize,
+}
+
+impl<'a> OpusPacket<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.is_empty() {
+            return Err(Error::Invalid("empty packet".into()));
+        }
+
+        let len = data.len().min(i32::MAX as usize) as opus_sys::opus_int32;
+        let mut toc = 0;
+        let mut frame_ptrs = [ptr::null(); MAX_FRAMES];
+        let mut sizes = [0; MAX_FRAMES];
+        let mut payload_offset = 0;
+
+        let ret =
+            unsafe { opus_sys::opus_packet_parse(data.as_ptr(), len, &mut toc, frame_ptrs.as_mut_ptr(), sizes.as_mut_ptr(), &mut payload_offset) };
+        if ret < 0 {
+            return Err(Error::Invalid(opus_error_string(ret)));
+        }
+
+        let count = ret as usize;
+        let mut frames = Vec::with_capacity(count);
+        let mut end = payload_offset as usize;
+
+        for (&frame_ptr, &size) in frame_ptrs.iter().zip(sizes.iter()).take(count) {
+            // Frames are returned as pointers into the packet
+            let offset = frame_ptr as usize - data.as_ptr() as usize;
+            let frame = data.get(offset..offset + size as usize).ok_or_else(|| Error::Invalid("packet frame size".into()))?;
+
+            end = end.max(offset + size as usize);
+            frames.push(frame);
+        }
+
+        Ok(OpusPacket {
+            data,
+            toc,
+            frames,
+            padding: data.len() - end,
+        })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn toc(&self) -> u8 {
+        self.toc
+    }
+
+    /// Configuration number, which selects the mode, bandwidth and frame
+    /// duration
+    pub fn config(&self) -> u8 {
+        self.toc >> 3
+    }
+
+    pub fn mode(&self) -> Mode {
+        match self.config() {
+            0..=11 => Mode::Silk,
+            12..=15 => Mode::Hybrid,
+            _ => Mode::Celt,
+        }
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        let bandwidth = unsafe { opus_sys::opus_packet_get_bandwidth(self.data.as_ptr()) };
+        Bandwidth::try_from(bandwidth).unwrap_or(Bandwidth::Fullband)
+    }
+
+    pub fn is_stereo(&self) -> bool {
+        unsafe { opus_sys::opus_packet_get_nb_channels(self.data.as_ptr()) == 2 }
+    }
+
+    pub fn channels(&self) -> u8 {
+        if self.is_stereo() {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn frames(&self) -> &[&'a [u8]] {
+        &self.frames
+    }
+
+    pub fn frame(&self, index: usize) -> Option<&'a [u8]> {
+        self.frames.get(index).copied()
+    }
+
+    /// Number of samples per frame at the given sample rate
+    pub fn samples_per_frame(&self, sample_rate: u32) -> u32 {
+        unsafe { opus_sys::opus_packet_get_samples_per_frame(self.data.as_ptr(), sample_rate as opus_sys::opus_int32) as u32 }
+    }
+
+    /// Number of samples of the whole packet at the given sample rate
+    pub fn samples(&self, sample_rate: u32) -> u32 {
+        self.samples_per_frame(sample_rate) * self.frames.len() as u32
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        // 400 samples per second is the 2.5ms granularity of the frame durations
+        Duration::from_micros(self.samples_per_frame(400) as u64 * 2500)
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.frame_duration() * self.frames.len() as u32
+    }
+
+    /// Number of padding bytes at the end of the packet
+    pub fn padding(&self) -> usize {
+        self.padding
+    }
+
+    /// Whether the packet carries in-band FEC data for the previous packet
+    pub fn has_lbrr(&self) -> bool {
+        let len = self.data.len().min(i32::MAX as usize) as opus_sys::opus_int32;
+        unsafe { opus_sys::opus_packet_has_lbrr(self.data.as_ptr(), len) > 0 }
+    }
+}
+
+/// Pads the packet held in the first `len` bytes of `data` so that it fills the
+/// whole buffer
+pub fn pad(data: &mut [u8], len: usize) -> Result<()> {
+    let ret = unsafe { opus_sys::opus_packet_pad(data.as_mut_ptr(), len as opus_sys::opus_int32, data.len() as opus_sys::opus_int32) };
+    if ret != opus_sys::OPUS_OK {
+        return Err(Error::Invalid(opus_error_string(ret)));
+    }
+
+    Ok(())
+}
+
+/// Removes all padding from the packet in place and returns its new length
+pub fn unpad(data: &mut [u8]) -> Result<usize> {
+    let ret = unsafe { opus_sys::opus_packet_unpad(data.as_mut_ptr(), data.len() as opus_sys::opus_int32) };
+    if ret < 0 {
+        return Err(Error::Invalid(opus_error_string(ret)));
+    }
+
+    Ok(ret as usize)
+}
+
+/// Pads the multistream packet held in the first `len` bytes of `data` so that
+/// it fills the whole buffer
+pub fn multistream_pad(data: &mut [u8], len: usize, streams: u8) -> Result<()> {
+    let ret = unsafe {
+        opus_sys::opus_multistream_packet_pad(data.as_mut_ptr(), len as opus_sys::opus_int32, data.len() as opus_sys::opus_int32, streams as c_int)
+    };
+    if ret != opus_sys::OPUS_OK {
+        return Err(Error::Invalid(opus_error_string(ret)));
+    }
+
+    Ok(())
+}
+
+/// Removes all padding from the multistream packet in place and returns its new
+/// length
+pub fn multistream_unpad(data: &mut [u8], streams: u8) -> Result<usize> {
+    let ret = unsafe { opus_sys::opus_multistream_packet_unpad(data.as_mut_ptr(), data.len() as opus_sys::opus_int32, streams as c_int) };
+    if ret < 0 {
+        return Err(Error::Invalid(opus_error_string(ret)));
+    }
+
+    Ok(ret as usize)
+}
+
+/// Removes all padding from the packet, which may be a multistream packet when
+/// `streams` is greater than 1
+pub fn unpad_packet(packet: &mut Packet, streams: u8) -> Result<()> {
+    let data = packet.data_mut().ok_or_else(|| Error::Invalid("packet not writable".into()))?;
+    let len = if streams > 1 {
+        multistream_unpad(data, streams)?
+    } else {
+        unpad(data)?
+    };
+
+    packet.truncate(len)
+}
+
+/// Number of samples of the packet at the given sample rate, which only reads
+/// the TOC and frame count bytes so it also applies to multistream packets
+pub fn packet_samples(data: &[u8], sample_rate: u32) -> Result<u32> {
+    let ret = unsafe { opus_sys::opus_packet_get_nb_samples(data.as_ptr(), data.len() as opus_sys::opus_int32, sample_rate as opus_sys::opus_int32) };
+    if ret < 0 {
+        return Err(Error::Invalid(opus_error_string(ret)));
+    }
+
+    Ok(ret as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 20ms CELT fullband frames, mono and stereo
+    const TOC: u8 = 0xf8;
+    const STEREO_TOC: u8 = 0xfc;
+
+    #[test]
+    fn parse_single_frame() {
+        let data = [TOC, 1, 2, 3];
+        let packet = OpusPacket::parse(&data).unwrap();
+        assert_eq!(packet.config(), 31);
+        assert_eq!(packet.mode(), Mode::Celt);
+        assert_eq!(packet.bandwidth(), Bandwidth::Fullband);
+        assert_eq!(packet.channels(), 1);
+        assert_eq!(packet.frames(), [&[1, 2, 3][..]]);
+        assert_eq!(packet.samples(48000), 960);
+        assert_eq!(packet.duration(), Duration::from_millis(20));
+        assert_eq!(packet.padding(), 0);
+        assert_eq!(packet_samples(&data, 48000).unwrap(), 960);
+
+        assert!(OpusPacket::parse(&[STEREO_TOC, 1]).unwrap().is_stereo());
+        assert!(OpusPacket::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn parse_two_frames() {
+        // Code 1, two frames of the same size
+        let packet = OpusPacket::parse(&[TOC | 1, 1, 2, 3, 4]).unwrap();
+        assert_eq!(packet.frames(), [&[1, 2][..], &[3, 4][..]]);
+        assert_eq!(packet.samples(48000), 1920);
+        assert!(OpusPacket::parse(&[TOC | 1, 1, 2, 3]).is_err());
+
+        // Code 2, two frames of different sizes
+        let packet = OpusPacket::parse(&[TOC | 2, 1, 1, 2, 3]).unwrap();
+        assert_eq!(packet.frames(), [&[1][..], &[2, 3][..]]);
+        assert_eq!(packet.duration(), Duration::from_millis(40));
+        assert!(OpusPacket::parse(&[TOC | 2, 5, 1]).is_err());
+    }
+
+    #[test]
+    fn parse_frame_count() {
+        // Code 3 CBR
+        let packet = OpusPacket::parse(&[TOC | 3, 3, 1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(packet.frame_count(), 3);
+        assert_eq!(packet.frame(2), Some(&[5, 6][..]));
+        assert_eq!(packet.samples(48000), 2880);
+
+        // Code 3 VBR
+        let packet = OpusPacket::parse(&[TOC | 3, 0x80 | 3, 1, 2, 1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(packet.frames(), [&[1][..], &[2, 3][..], &[4, 5, 6][..]]);
+        assert_eq!(packet.padding(), 0);
+
+        // Code 3 with 2 bytes of padding
+        let data = [TOC | 3, 0x40 | 2, 2, 1, 2, 3, 4, 0, 0];
+        let packet = OpusPacket::parse(&data).unwrap();
+        assert_eq!(packet.frames(), [&[1, 2][..], &[3, 4][..]]);
+        assert_eq!(packet.padding(), 2);
+
+        // Truncated
+        assert!(OpusPacket::parse(&[TOC | 3]).is_err());
+        assert!(OpusPacket::parse(&[TOC | 3, 0x80 | 2, 5, 1]).is_err());
+        assert!(OpusPacket::parse(&[TOC | 3, 0x40 | 2, 10, 1, 2]).is_err());
+
+        // 7 frames of 20ms exceed 120ms
+        assert!(OpusPacket::parse(&[TOC | 3, 7, 1, 2, 3, 4, 5, 6, 7]).is_err());
+        assert!(packet_samples(&[TOC | 3, 7], 48000).is_err());
+        assert!(OpusPacket::parse(&[TOC | 3, 6, 1, 2, 3, 4, 5, 6]).is_ok());
+    }
+
+    #[test]
+    fn mode_and_bandwidth() {
+        let cases = [
+            (0x00, Mode::Silk, Bandwidth::Narrowband, 10000),
+            (0x48, Mode::Silk, Bandwidth::Wideband, 20000),
+            (0x60, Mode::Hybrid, Bandwidth::SuperWideband, 10000),
+            (0x78, Mode::Hybrid, Bandwidth::Fullband, 20000),
+            (0x80, Mode::Celt, Bandwidth::Narrowband, 2500),
+            (0xa8, Mode::Celt, Bandwidth::Wideband, 5000),
+        ];
+
+        for (toc, mode, bandwidth, micros) in cases {
+            let data = [toc, 0];
+            let packet = OpusPacket::parse(&data).unwrap();
+            assert_eq!(packet.mode(), mode);
+            assert_eq!(packet.bandwidth(), bandwidth);
+            assert_eq!(packet.frame_duration(), Duration::from_micros(micros));
+        }
+    }
+
+    #[test]
+    fn has_lbrr() {
+        // The LBRR flag follows the VAD flag of the 20ms SILK frame
+        assert!(OpusPacket::parse(&[0x48, 0x40, 0]).unwrap().has_lbrr());
+        assert!(!OpusPacket::parse(&[0x48, 0x80, 0]).unwrap().has_lbrr());
+        assert!(!OpusPacket::parse(&[TOC, 0xff, 0]).unwrap().has_lbrr());
+    }
+
+    #[test]
+    fn pad_round_trip() {
+        let mut data = [0; 16];
+        data[..4].copy_from_slice(&[TOC, 1, 2, 3]);
+        pad(&mut data, 4).unwrap();
+
+        let packet = OpusPacket::parse(&data).unwrap();
+        assert_eq!(packet.frames(), [&[1, 2, 3][..]]);
+        assert_eq!(packet.padding(), 16 - 6);
+
+        assert_eq!(unpad(&mut data).unwrap(), 4);
+        assert_eq!(data[..4], [TOC, 1, 2, 3]);
+
+        // The buffer must hold the packet
+        assert!(pad(&mut data[..3], 4).is_err());
+    }
+
+    #[test]
+    fn multistream_pad_round_trip() {
+        // All streams but the last use the self-delimited framing
+        let original = [TOC, 2, 1, 2, TOC, 3, 4];
+        let mut packet = Packet::new(20);
+        let data = packet.data_mut().unwrap();
+        data[..original.len()].copy_from_slice(&original);
+        multistream_pad(data, original.len(), 2).unwrap();
+        assert_eq!(packet_samples(packet.data(), 48000).unwrap(), 960);
+
+        unpad_packet(&mut packet, 2).unwrap();
+        assert_eq!(packet.data(), original);
+    }
+}
@@ -0,0 +1,240 @@
+use media_codec::packet::Packet;
+use media_core::{error::Error, rational::Rational64, Result};
+
+use crate::{opus_error_string, opus_sys, packet::OpusPacket};
+
+// Worst case overhead of the TOC byte, frame count byte and frame lengths
+const MAX_OVERHEAD: usize = 2 + 2 * 48;
+
+/// Merges Opus packets into multi-frame packets and splits them back, as
+/// described in RFC 6716 section 3.2
+pub struct Repacketizer {
+    repacketizer: *mut opus_sys::OpusRepacketizer,
+    // The repacketizer only keeps pointers to the added packets, so they are
+    // copied here until the next reset
+    packets: Vec<Vec<u8>>,
+}
+
+unsafe impl Send for Repacketizer {}
+unsafe impl Sync for Repacketizer {}
+
+impl Repacketizer {
+    pub fn new() -> Result<Self> {
+        let repacketizer = unsafe { opus_sys::opus_repacketizer_create() };
+        if repacketizer.is_null() {
+            return Err(Error::CreationFailed("repacketizer".into()));
+        }
+
+        Ok(Repacketizer {
+            repacketizer,
+            packets: Vec::new(),
+        })
+    }
+
+    /// Adds a packet, which must share the TOC configuration of the packets
+    /// already added and keep the total duration within 120ms
+    pub fn push(&mut self, data: &[u8]) -> Result<()> {
+        let data = data.to_vec();
+
+        let ret = unsafe { opus_sys::opus_repacketizer_cat(self.repacketizer, data.as_ptr(), data.len() as opus_sys::opus_int32) };
+        // The repacketizer state is left unchanged on failure
+        if ret == opus_sys::OPUS_INVALID_PACKET && !self.packets.is_empty() {
+            return Err(Error::Invalid("packet configuration mismatch or duration over 120ms".into()));
+        } else if ret != opus_sys::OPUS_OK {
+            return Err(Error::Invalid(opus_error_string(ret)));
+        }
+
+        self.packets.push(data);
+
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> usize {
+        unsafe { opus_sys::opus_repacketizer_get_nb_frames(self.repacketizer) as usize }
+    }
+
+    /// Outputs all the added frames as a single packet and resets the
+    /// repacketizer
+    pub fn output(&mut self) -> Result<Vec<u8>> {
+        let data = self.output_range(0, self.frame_count())?;
+        self.reset();
+
+        Ok(data)
+    }
+
+    /// Outputs the frames in the range `begin..end` as a single packet
+    pub fn output_range(&mut self, begin: usize, end: usize) -> Result<Vec<u8>> {
+        if begin >= end || end > self.frame_count() {
+            return Err(Error::Invalid("frame range".into()));
+        }
+
+        let size = self.packets.iter().map(Vec::len).sum::<usize>() + MAX_OVERHEAD;
+        let mut data = vec![0; size];
+
+        let ret = unsafe {
+            opus_sys::opus_repacketizer_out_range(self.repacketizer, begin as i32, end as i32, data.as_mut_ptr(), size as opus_sys::opus_int32)
+        };
+        if ret < 0 {
+            return Err(Error::Failed(opus_error_string(ret)));
+        }
+
+        data.truncate(ret as usize);
+
+        Ok(data)
+    }
+
+    pub fn reset(&mut self) {
+        unsafe { opus_sys::opus_repacketizer_init(self.repacketizer) };
+        self.packets.clear();
+    }
+
+    /// Merges the packets into a single packet
+    pub fn merge(&mut self, packets: &[&[u8]]) -> Result<Vec<u8>> {
+        self.reset();
+
+        for data in packets {
+            if let Err(err) = self.push(data) {
+                self.reset();
+                return Err(err);
+            }
+        }
+
+        self.output()
+    }
+
+    /// Splits the packet into single-frame packets
+    pub fn split(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.reset();
+        self.push(data)?;
+
+        let packets = (0..self.frame_count()).map(|index| self.output_range(index, index + 1)).collect();
+        self.reset();
+
+        packets
+    }
+
+    /// Merges the packets into a single packet, which starts at the timestamp
+    /// of the first packet and lasts for their total duration
+    pub fn merge_packets(&mut self, packets: &[Packet]) -> Result<Packet<'static>> {
+        let first = packets.first().ok_or_else(|| Error::Invalid("no packet".into()))?;
+        let data: Vec<&[u8]> = packets.iter().map(|packet| packet.data()).collect();
+
+        let mut merged = Self::new_packet(&self.merge(&data)?)?;
+        merged.pts = first.pts;
+        merged.dts = first.dts;
+        merged.duration = packets.iter().map(|packet| packet.duration).sum();
+        merged.time_base = first.time_base;
+
+        Ok(merged)
+    }
+
+    /// Splits the packet into single-frame packets with consecutive timestamps
+    pub fn split_packet(&mut self, packet: &Packet) -> Result<Vec<Packet<'static>>> {
+        let frame_duration = OpusPacket::parse(packet.data())?.samples_per_frame(48000) as i64;
+
+        self.split(packet.data())?
+            .iter()
+            .enumerate()
+            .map(|(index, data)| {
+                let mut split = Self::new_packet(data)?;
+                split.time_base = packet.time_base;
+
+                if let Some(time_base) = packet.time_base {
+                    let to_time = |samples: i64| (Rational64::new(samples, 48000) / time_base).to_integer();
+                    let offset = to_time(frame_duration * index as i64);
+
+                    split.pts = packet.pts.map(|pts| pts + offset);
+                    split.dts = packet.dts.map(|dts| dts + offset);
+                    split.duration = Some(to_time(frame_duration * (index as i64 + 1)) - offset);
+                }
+
+                Ok(split)
+            })
+            .collect()
+    }
+
+    fn new_packet(data: &[u8]) -> Result<Packet<'static>> {
+        let mut packet = Packet::new(data.len());
+        packet.data_mut().ok_or_else(|| Error::Invalid("packet not writable".into()))?.copy_from_slice(data);
+
+        Ok(packet)
+    }
+}
+
+impl Drop for Repacketizer {
+    fn drop(&mut self) {
+        unsafe { opus_sys::opus_repacketizer_destroy(self.repacketizer) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 20ms CELT fullband frames, mono and stereo
+    const TOC: u8 = 0xf8;
+    const STEREO_TOC: u8 = 0xfc;
+
+    fn packet(data: &[u8], pts: i64, duration: i64, time_base: Rational64) -> Packet<'_> {
+        let mut packet = Packet::from_slice(data);
+        packet.pts = Some(pts);
+        packet.duration = Some(duration);
+        packet.time_base = Some(time_base);
+        packet
+    }
+
+    #[test]
+    fn merge_split_round_trip() {
+        let packets: [&[u8]; 3] = [&[TOC, 1], &[TOC, 2, 3], &[TOC, 4, 5, 6]];
+        let mut repacketizer = Repacketizer::new().unwrap();
+
+        let merged = repacketizer.merge(&packets).unwrap();
+        let parsed = OpusPacket::parse(&merged).unwrap();
+        assert_eq!(parsed.toc(), TOC | 3);
+        assert_eq!(parsed.frames(), [&[1][..], &[2, 3][..], &[4, 5, 6][..]]);
+        assert_eq!(parsed.samples(48000), 2880);
+
+        assert_eq!(repacketizer.split(&merged).unwrap(), packets);
+    }
+
+    #[test]
+    fn merge_rejected() {
+        let mut repacketizer = Repacketizer::new().unwrap();
+
+        // Mono and stereo frames cannot share a packet
+        assert!(repacketizer.merge(&[&[TOC, 1], &[STEREO_TOC, 2]]).is_err());
+        assert_eq!(repacketizer.frame_count(), 0);
+
+        // Up to 120ms, that is 6 frames of 20ms
+        let packets = [&[TOC, 1][..]; 7];
+        assert!(repacketizer.merge(&packets).is_err());
+        assert_eq!(OpusPacket::parse(&repacketizer.merge(&packets[..6]).unwrap()).unwrap().frame_count(), 6);
+
+        repacketizer.push(&[TOC, 1]).unwrap();
+        assert!(repacketizer.push(&[STEREO_TOC, 2]).is_err());
+        assert_eq!(repacketizer.frame_count(), 1);
+        assert!(repacketizer.output_range(0, 2).is_err());
+    }
+
+    #[test]
+    fn packet_timestamps() {
+        let mut repacketizer = Repacketizer::new().unwrap();
+        let time_base = Rational64::new(1, 48000);
+
+        let merged = repacketizer.merge_packets(&[packet(&[TOC, 1], 100, 960, time_base), packet(&[TOC, 2], 1060, 960, time_base)]).unwrap();
+        assert_eq!(merged.data(), [TOC | 1, 1, 2]);
+        assert_eq!(merged.pts, Some(100));
+        assert_eq!(merged.duration, Some(1920));
+        assert_eq!(merged.time_base, Some(time_base));
+
+        let split = repacketizer.split_packet(&merged).unwrap();
+        let timestamps: Vec<_> = split.iter().map(|packet| (packet.pts, packet.duration)).collect();
+        assert_eq!(timestamps, [(Some(100), Some(960)), (Some(1060), Some(960))]);
+
+        // 2.5ms frames in a 1ms time base
+        let data = repacketizer.merge(&[&[0x80, 1], &[0x80, 2], &[0x80, 3], &[0x80, 4]]).unwrap();
+        let split = repacketizer.split_packet(&packet(&data, 10, 10, Rational64::new(1, 1000))).unwrap();
+        let timestamps: Vec<_> = split.iter().map(|packet| (packet.pts.unwrap(), packet.duration.unwrap())).collect();
+        assert_eq!(timestamps, [(10, 2), (12, 3), (15, 2), (17, 3)]);
+    }
+}
@@ -0,0 +1,294 @@
+use media_codec::packet::Packet;
+use media_core::{error::Error, rational::Rational64, Result};
+
+use crate::packet::packet_samples;
+
+// The RTP clock rate of Opus is always 48kHz, as defined in RFC 7587
+const RTP_CLOCK_RATE: i64 = 48000;
+const RTP_VERSION: u8 = 2;
+const RTP_HEADER_SIZE: usize = 12;
+// Packets of up to 2 bytes only carry a TOC byte and frame count, which is how
+// libopus signals discontinuous transmission
+const DTX_PACKET_MAX_SIZE: usize = 2;
+
+/// RTP packet, as defined in RFC 3550
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RtpPacket {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: Vec<u8>,
+}
+
+impl RtpPacket {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < RTP_HEADER_SIZE || data[0] >> 6 != RTP_VERSION {
+            return Err(Error::Invalid("RTP packet".into()));
+        }
+
+        let padding = data[0] & 0x20 != 0;
+        let extension = data[0] & 0x10 != 0;
+        let csrc_count = (data[0] & 0x0f) as usize;
+        let mut start = RTP_HEADER_SIZE + csrc_count * 4;
+        let mut end = data.len();
+
+        if extension {
+            let header = data.get(start..start + 4).ok_or_else(|| Error::Invalid("RTP header extension".into()))?;
+            start += 4 + u16::from_be_bytes([header[2], header[3]]) as usize * 4;
+        }
+
+        if padding {
+            end = end.saturating_sub(data[end - 1] as usize);
+        }
+
+        let payload = data.get(start..end).ok_or_else(|| Error::Invalid("RTP packet size".into()))?;
+
+        Ok(RtpPacket {
+            marker: data[1] & 0x80 != 0,
+            payload_type: data[1] & 0x7f,
+            sequence_number: u16::from_be_bytes([data[2], data[3]]),
+            timestamp: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            ssrc: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            payload: payload.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(RTP_HEADER_SIZE + self.payload.len());
+
+        data.push(RTP_VERSION << 6);
+        data.push((self.marker as u8) << 7 | self.payload_type & 0x7f);
+        data.extend_from_slice(&self.sequence_number.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        data.extend_from_slice(&self.ssrc.to_be_bytes());
+        data.extend_from_slice(&self.payload);
+
+        data
+    }
+}
+
+/// Wraps Opus packets in RTP packets, as defined in RFC 7587
+pub struct OpusRtpPayloader {
+    payload_type: u8,
+    ssrc: u32,
+    sequence_number: u16,
+    // Random offset of the RTP timestamp
+    timestamp_offset: u32,
+    // Position at 48kHz expected for the next packet
+    next_position: Option<i64>,
+    // Whether the last packet was a DTX packet
+    dtx: bool,
+}
+
+impl OpusRtpPayloader {
+    pub fn new(payload_type: u8, ssrc: u32, initial_sequence_number: u16, timestamp_offset: u32) -> Self {
+        OpusRtpPayloader {
+            payload_type,
+            ssrc,
+            sequence_number: initial_sequence_number,
+            timestamp_offset,
+            next_position: None,
+            dtx: false,
+        }
+    }
+
+    /// Wraps the packet, whose pts maps to the RTP timestamp at 48kHz. The
+    /// marker bit is set on the first packet and on the first packet after
+    /// a silence sent as DTX packets or not sent at all
+    pub fn pay(&mut self, packet: &Packet) -> Result<RtpPacket> {
+        let data = packet.data();
+        let samples = packet_samples(data, RTP_CLOCK_RATE as u32)? as i64;
+        let position = match (packet.pts, packet.time_base) {
+            (Some(pts), Some(time_base)) => (time_base * pts * RTP_CLOCK_RATE).to_integer(),
+            _ => self.next_position.unwrap_or(0),
+        };
+
+        let dtx = data.len() <= DTX_PACKET_MAX_SIZE;
+        let marker = !dtx && (self.dtx || self.next_position.is_none_or(|next_position| position > next_position));
+
+        let rtp_packet = RtpPacket {
+            marker,
+            payload_type: self.payload_type,
+            sequence_number: self.sequence_number,
+            timestamp: self.timestamp_offset.wrapping_add(position as u32),
+            ssrc: self.ssrc,
+            payload: data.to_vec(),
+        };
+
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.next_position = Some(position + samples);
+        self.dtx = dtx;
+
+        Ok(rtp_packet)
+    }
+}
+
+/// Opus packet extracted from an RTP packet
+pub struct OpusRtpPayload {
+    pub packet: Packet<'static>,
+    /// Number of packets lost right before this one, as detected from the
+    /// sequence numbers, to be passed to the `lost_packets` option of
+    /// `OpusDecoder` so that losses are told apart from DTX silences
+    pub lost: u16,
+}
+
+/// Extracts Opus packets from RTP packets, as defined in RFC 7587
+#[derive(Default)]
+pub struct OpusRtpDepayloader {
+    sequence_number: Option<u16>,
+    // First RTP timestamp and extended last RTP timestamp, to unwrap them
+    first_timestamp: u32,
+    last_timestamp: i64,
+}
+
+impl OpusRtpDepayloader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Extracts the packet, timestamped at 48kHz from the first RTP packet so
+    /// that `OpusDecoder` recovers the gaps left by lost packets with FEC or
+    /// PLC. Returns `None` for late or duplicated packets
+    pub fn depay(&mut self, rtp_packet: &RtpPacket) -> Result<Option<OpusRtpPayload>> {
+        if rtp_packet.payload.is_empty() {
+            return Err(Error::Invalid("empty RTP payload".into()));
+        }
+
+        let lost = match self.sequence_number {
+            Some(sequence_number) => {
+                let delta = rtp_packet.sequence_number.wrapping_sub(sequence_number) as i16;
+                if delta <= 0 {
+                    return Ok(None);
+                }
+
+                (delta - 1) as u16
+            }
+            None => {
+                self.first_timestamp = rtp_packet.timestamp;
+                self.last_timestamp = 0;
+                0
+            }
+        };
+
+        // RTP timestamps wrap around, so they are unwrapped relative to the last one
+        let last_timestamp = self.first_timestamp.wrapping_add(self.last_timestamp as u32);
+        let position = self.last_timestamp + rtp_packet.timestamp.wrapping_sub(last_timestamp) as i32 as i64;
+        let samples = packet_samples(&rtp_packet.payload, RTP_CLOCK_RATE as u32)? as i64;
+
+        let mut packet = Packet::new(rtp_packet.payload.len());
+        packet.data_mut().ok_or_else(|| Error::Invalid("packet not writable".into()))?.copy_from_slice(&rtp_packet.payload);
+        packet.pts = Some(position);
+        packet.duration = Some(samples);
+        packet.time_base = Some(Rational64::new(1, RTP_CLOCK_RATE));
+
+        self.sequence_number = Some(rtp_packet.sequence_number);
+        self.last_timestamp = position;
+
+        Ok(Some(OpusRtpPayload {
+            packet,
+            lost,
+        }))
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 20ms CELT fullband frame
+    const TOC: u8 = 0xf8;
+
+    fn rtp_packet(sequence_number: u16, timestamp: u32) -> RtpPacket {
+        RtpPacket {
+            marker: false,
+            payload_type: 111,
+            sequence_number,
+            timestamp,
+            ssrc: 0x12345678,
+            payload: vec![TOC, 1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let rtp_packet = RtpPacket {
+            marker: true,
+            ..rtp_packet(0xfffe, 0xfffffff0)
+        };
+        assert_eq!(RtpPacket::parse(&rtp_packet.to_bytes()).unwrap(), rtp_packet);
+    }
+
+    #[test]
+    fn parse_csrc_extension_padding() {
+        let mut data = rtp_packet(1, 2).to_bytes();
+        let payload = data.split_off(RTP_HEADER_SIZE);
+
+        // One CSRC, a one-word header extension and 3 bytes of padding
+        data[0] |= 0x20 | 0x10 | 1;
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(&[0xbe, 0xde, 0, 1, 0, 0, 0, 0]);
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&[0, 0, 3]);
+
+        assert_eq!(RtpPacket::parse(&data).unwrap().payload, payload);
+        assert!(RtpPacket::parse(&data[..RTP_HEADER_SIZE + 6]).is_err());
+        assert!(RtpPacket::parse(&[0x40; RTP_HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn depay_wrap_around() {
+        let mut depayloader = OpusRtpDepayloader::new();
+
+        let payload = depayloader.depay(&rtp_packet(0xffff, 0xffffff00)).unwrap().unwrap();
+        assert_eq!(payload.packet.pts, Some(0));
+        assert_eq!(payload.packet.duration, Some(960));
+        assert_eq!(payload.lost, 0);
+
+        // Both the sequence number and the timestamp wrap around
+        let payload = depayloader.depay(&rtp_packet(0, 0xffffff00u32.wrapping_add(960))).unwrap().unwrap();
+        assert_eq!(payload.packet.pts, Some(960));
+        assert_eq!(payload.lost, 0);
+
+        let payload = depayloader.depay(&rtp_packet(3, 0xffffff00u32.wrapping_add(960 * 4))).unwrap().unwrap();
+        assert_eq!(payload.packet.pts, Some(960 * 4));
+        assert_eq!(payload.lost, 2);
+
+        // Late and duplicated packets are dropped
+        assert!(depayloader.depay(&rtp_packet(2, 0xffffff00u32.wrapping_add(960 * 3))).unwrap().is_none());
+        assert!(depayloader.depay(&rtp_packet(3, 0xffffff00u32.wrapping_add(960 * 4))).unwrap().is_none());
+    }
+
+    #[test]
+    fn pay_marker_and_timestamp() {
+        let mut payloader = OpusRtpPayloader::new(111, 0x12345678, 0xffff, 0xfffffe00);
+        let mut pay = |pts: i64, data: &[u8]| {
+            let mut packet = Packet::from_slice(data);
+            packet.pts = Some(pts);
+            packet.time_base = Some(Rational64::new(1, RTP_CLOCK_RATE));
+            payloader.pay(&packet).unwrap()
+        };
+
+        let first = pay(0, &[TOC, 1, 2, 3]);
+        assert!(first.marker);
+        assert_eq!(first.sequence_number, 0xffff);
+        assert_eq!(first.timestamp, 0xfffffe00);
+
+        let second = pay(960, &[TOC, 1, 2, 3]);
+        assert!(!second.marker);
+        assert_eq!(second.sequence_number, 0);
+        assert_eq!(second.timestamp, 0xfffffe00u32.wrapping_add(960));
+
+        // DTX packets never carry the marker, which is set on the packet that ends
+        // the silence
+        assert!(!pay(1920, &[TOC]).marker);
+        assert!(pay(2880, &[TOC, 1, 2, 3]).marker);
+        // So is a gap in the timestamps
+        assert!(pay(4800, &[TOC, 1, 2, 3]).marker);
+    }
+}
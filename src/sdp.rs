@@ -0,0 +1,291 @@
+use std::fmt;
+
+use media_core::{error::Error, variant::Variant, Result};
+
+#[cfg(feature = "encoder")]
+use crate::encoder::OpusEncoderConfig;
+use crate::{Application, Bandwidth};
+
+// Frame durations supported by Opus, in milliseconds
+const FRAME_DURATIONS: [f32; 9] = [2.5, 5.0, 10.0, 20.0, 40.0, 60.0, 80.0, 100.0, 120.0];
+// Sample rates supported by the Opus decoder
+const DECODER_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+/// Opus format parameters of the SDP `a=fmtp` attribute, as defined in RFC 7587
+///
+/// The parameters without the `sprop-` prefix describe what the receiver of the
+/// SDP prefers to receive, so they configure the local encoder, while the
+/// `sprop-` ones describe what it sends, so they configure the local decoder
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpusFmtp {
+    pub max_playback_rate: Option<u32>,
+    pub sprop_max_capture_rate: Option<u32>,
+    pub max_average_bitrate: Option<u32>,
+    pub stereo: Option<bool>,
+    pub sprop_stereo: Option<bool>,
+    pub cbr: Option<bool>,
+    pub use_inband_fec: Option<bool>,
+    pub use_dtx: Option<bool>,
+    /// Preferred packet duration, in milliseconds
+    pub ptime: Option<u32>,
+    /// Maximum packet duration, in milliseconds
+    pub max_ptime: Option<u32>,
+    /// Minimum packet duration, in milliseconds
+    pub min_ptime: Option<u32>,
+}
+
+impl OpusFmtp {
+    /// Parses the parameters, either alone or as a whole `a=fmtp:` line, and
+    /// ignores unknown ones
+    pub fn parse(fmtp: &str) -> Result<Self> {
+        let mut params = fmtp.trim();
+
+        if let Some(line) = params.strip_prefix("a=fmtp:") {
+            // Skip the payload type
+            params = line.split_once(char::is_whitespace).map_or("", |(_, params)| params.trim());
+        }
+
+        let mut fmtp = OpusFmtp::default();
+
+        for param in params.split(';').map(str::trim).filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').ok_or_else(|| Error::Invalid(format!("fmtp parameter {}", param).into()))?;
+            let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+
+            let parse_u32 = || value.parse::<u32>().map_err(|_| Error::Invalid(format!("fmtp {} value", key).into()));
+            let parse_bool = || match value {
+                "0" => Ok(false),
+                "1" => Ok(true),
+                _ => Err(Error::Invalid(format!("fmtp {} value", key).into())),
+            };
+
+            match key.as_str() {
+                "maxplaybackrate" => fmtp.max_playback_rate = Some(parse_u32()?),
+                "sprop-maxcapturerate" => fmtp.sprop_max_capture_rate = Some(parse_u32()?),
+                "maxaveragebitrate" => fmtp.max_average_bitrate = Some(parse_u32()?),
+                "stereo" => fmtp.stereo = Some(parse_bool()?),
+                "sprop-stereo" => fmtp.sprop_stereo = Some(parse_bool()?),
+                "cbr" => fmtp.cbr = Some(parse_bool()?),
+                "useinbandfec" => fmtp.use_inband_fec = Some(parse_bool()?),
+                "usedtx" => fmtp.use_dtx = Some(parse_bool()?),
+                "ptime" => fmtp.ptime = Some(parse_u32()?),
+                "maxptime" => fmtp.max_ptime = Some(parse_u32()?),
+                "minptime" => fmtp.min_ptime = Some(parse_u32()?),
+                _ => {}
+            }
+        }
+
+        Ok(fmtp)
+    }
+
+    /// Widest bandwidth worth encoding for the maximum playback rate of the
+    /// receiver
+    pub fn max_bandwidth(&self) -> Option<Bandwidth> {
+        self.max_playback_rate.map(|rate| match rate {
+            0..=8000 => Bandwidth::Narrowband,
+            8001..=12000 => Bandwidth::Mediumband,
+            12001..=16000 => Bandwidth::Wideband,
+            16001..=24000 => Bandwidth::SuperWideband,
+            _ => Bandwidth::Fullband,
+        })
+    }
+
+    /// Longest frame duration, in milliseconds, within the packet durations
+    /// preferred by the receiver
+    pub fn frame_duration(&self) -> Option<f32> {
+        let ptime = match (self.ptime, self.max_ptime) {
+            (Some(ptime), Some(max_ptime)) => ptime.min(max_ptime),
+            (ptime, max_ptime) => ptime.or(max_ptime)?,
+        } as f32;
+        let min_ptime = self.min_ptime.unwrap_or(0) as f32;
+
+        FRAME_DURATIONS.iter().rev().find(|&&duration| duration <= ptime && duration >= min_ptime).or(FRAME_DURATIONS.first()).copied()
+    }
+
+    /// Number of channels the encoder is limited to, if the receiver only wants
+    /// mono. A receiver preferring stereo still decodes mono, so the encoder
+    /// keeps choosing the channels from the input
+    pub fn encoder_channels(&self) -> Option<u8> {
+        (self.stereo == Some(false)).then_some(1)
+    }
+
+    /// `OpusEncoder` options matching the preferences of the receiver
+    pub fn encoder_options(&self) -> Variant {
+        let mut options = Variant::new_dict();
+
+        if let Some(max_bandwidth) = self.max_bandwidth() {
+            options.dict_set("max_bandwidth", max_bandwidth.as_str().into());
+        }
+
+        if let Some(bit_rate) = self.max_average_bitrate {
+            // Opus supports bitrates from 6 to 510 kbit/s
+            options.dict_set("bit_rate", bit_rate.clamp(6000, 510000).into());
+        }
+
+        if let Some(fec) = self.use_inband_fec {
+            options.dict_set("fec", fec.into());
+        }
+
+        if let Some(dtx) = self.use_dtx {
+            options.dict_set("dtx", dtx.into());
+        }
+
+        if let Some(cbr) = self.cbr {
+            let vbr = if cbr {
+                "cbr"
+            } else {
+                "vbr"
+            };
+            options.dict_set("vbr", vbr.into());
+        }
+
+        if let Some(frame_duration) = self.frame_duration() {
+            options.dict_set("frame_duration", frame_duration.into());
+
+            // Frames shorter than 10ms need the lowdelay application, which can only be
+            // chosen when creating the encoder
+            if frame_duration < 10.0 {
+                options.dict_set("application", Application::LowDelay.as_str().into());
+            }
+        }
+
+        if let Some(channels) = self.encoder_channels() {
+            options.dict_set("force_channels", (channels as u32).into());
+        }
+
+        options
+    }
+
+    /// `OpusEncoder` configuration matching the preferences of the receiver
+    #[cfg(feature = "encoder")]
+    pub fn encoder_config(&self) -> Result<OpusEncoderConfig> {
+        OpusEncoderConfig::from_variant(&self.encoder_options())
+    }
+
+    /// Lowest decoder sample rate covering the capture rate of the sender
+    pub fn decoder_sample_rate(&self) -> Option<u32> {
+        self.sprop_max_capture_rate.map(|rate| DECODER_SAMPLE_RATES.iter().copied().find(|&sample_rate| sample_rate >= rate).unwrap_or(48000))
+    }
+
+    pub fn decoder_channels(&self) -> Option<u8> {
+        self.sprop_stereo.map(|stereo| {
+            if stereo {
+                2
+            } else {
+                1
+            }
+        })
+    }
+
+    /// `OpusDecoder` options matching the stream of the sender
+    pub fn decoder_options(&self) -> Variant {
+        let mut options = Variant::new_dict();
+
+        // The FEC data is only worth decoding if the sender was asked for it
+        if let Some(fec) = self.use_inband_fec {
+            options.dict_set("fec", fec.into());
+        }
+
+        options
+    }
+}
+
+impl fmt::Display for OpusFmtp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params = [
+            ("maxplaybackrate", self.max_playback_rate),
+            ("sprop-maxcapturerate", self.sprop_max_capture_rate),
+            ("maxaveragebitrate", self.max_average_bitrate),
+            ("stereo", self.stereo.map(u32::from)),
+            ("sprop-stereo", self.sprop_stereo.map(u32::from)),
+            ("cbr", self.cbr.map(u32::from)),
+            ("useinbandfec", self.use_inband_fec.map(u32::from)),
+            ("usedtx", self.use_dtx.map(u32::from)),
+            ("ptime", self.ptime),
+            ("maxptime", self.max_ptime),
+            ("minptime", self.min_ptime),
+        ];
+
+        let mut separator = "";
+        for (key, value) in params {
+            if let Some(value) = value {
+                write!(f, "{}{}={}", separator, key, value)?;
+                separator = ";";
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_display() {
+        let fmtp = OpusFmtp::parse("a=fmtp:111 minptime=10; useinbandfec=1;stereo=0;SPROP-STEREO=1;unknown=2").unwrap();
+        assert_eq!(
+            fmtp,
+            OpusFmtp {
+                stereo: Some(false),
+                sprop_stereo: Some(true),
+                use_inband_fec: Some(true),
+                min_ptime: Some(10),
+                ..Default::default()
+            }
+        );
+        assert_eq!(fmtp.to_string(), "stereo=0;sprop-stereo=1;useinbandfec=1;minptime=10");
+        assert_eq!(OpusFmtp::parse(&fmtp.to_string()).unwrap(), fmtp);
+
+        assert!(OpusFmtp::parse("stereo=2").is_err());
+        assert!(OpusFmtp::parse("maxplaybackrate").is_err());
+    }
+
+    #[test]
+    fn encoder_options() {
+        let fmtp = OpusFmtp::parse("maxplaybackrate=16000;maxaveragebitrate=1000000;cbr=1;usedtx=1;ptime=30").unwrap();
+        let options = fmtp.encoder_options();
+
+        assert_eq!(options.dict_get_string("max_bandwidth").as_deref(), Some("wideband"));
+        assert_eq!(options.dict_get_uint32("bit_rate"), Some(510000));
+        assert_eq!(options.dict_get_string("vbr").as_deref(), Some("cbr"));
+        assert_eq!(options.dict_get_bool("dtx"), Some(true));
+        assert_eq!(options.dict_get_float("frame_duration"), Some(20.0));
+        assert_eq!(options.dict_get("force_channels"), None);
+    }
+
+    #[test]
+    fn max_ptime() {
+        let fmtp = OpusFmtp::parse("ptime=40;maxptime=25").unwrap();
+        assert_eq!(fmtp.to_string(), "ptime=40;maxptime=25");
+        assert_eq!(fmtp.frame_duration(), Some(20.0));
+        assert_eq!(OpusFmtp::parse("maxptime=60").unwrap().frame_duration(), Some(60.0));
+    }
+
+    #[cfg(feature = "encoder")]
+    #[test]
+    fn short_ptime() {
+        let config = OpusFmtp::parse("ptime=5").unwrap().encoder_config().unwrap();
+        assert_eq!(config.frame_duration.millis(), 5.0);
+        assert_eq!(config.application, Application::LowDelay);
+
+        let config = OpusFmtp::parse("ptime=10").unwrap().encoder_config().unwrap();
+        assert_eq!(config.application, Application::Audio);
+    }
+
+    #[test]
+    fn encoder_channels() {
+        assert_eq!(OpusFmtp::parse("stereo=0").unwrap().encoder_channels(), Some(1));
+        // Preferring stereo leaves the choice to the encoder
+        assert_eq!(OpusFmtp::parse("stereo=1").unwrap().encoder_channels(), None);
+        assert_eq!(OpusFmtp::default().encoder_channels(), None);
+    }
+
+    #[test]
+    fn decoder_parameters() {
+        let fmtp = OpusFmtp::parse("sprop-maxcapturerate=22050;sprop-stereo=0").unwrap();
+
+        assert_eq!(fmtp.decoder_sample_rate(), Some(24000));
+        assert_eq!(fmtp.decoder_channels(), Some(1));
+    }
+}
@@ -74,6 +74,11 @@ pub const OPUS_FRAMESIZE_80_MS: i32 = 5007;
 pub const OPUS_FRAMESIZE_100_MS: i32 = 5008;
 pub const OPUS_FRAMESIZE_120_MS: i32 = 5009;
 pub const OPUS_RESET_STATE: i32 = 4028;
+pub const OPUS_MULTISTREAM_GET_ENCODER_STATE_REQUEST: i32 = 5120;
+pub const OPUS_MULTISTREAM_GET_DECODER_STATE_REQUEST: i32 = 5122;
+pub const OPUS_PROJECTION_GET_DEMIXING_MATRIX_GAIN_REQUEST: i32 = 6001;
+pub const OPUS_PROJECTION_GET_DEMIXING_MATRIX_SIZE_REQUEST: i32 = 6003;
+pub const OPUS_PROJECTION_GET_DEMIXING_MATRIX_REQUEST: i32 = 6005;
 pub type opus_int32 = ::std::os::raw::c_int;
 pub type opus_uint32 = ::std::os::raw::c_uint;
 pub type opus_int16 = ::std::os::raw::c_short;
@@ -103,6 +108,26 @@ pub struct OpusDRED {
 pub struct OpusRepacketizer {
     _unused: [u8; 0],
 }
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct OpusMSEncoder {
+    _unused: [u8; 0],
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct OpusMSDecoder {
+    _unused: [u8; 0],
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct OpusProjectionEncoder {
+    _unused: [u8; 0],
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct OpusProjectionDecoder {
+    _unused: [u8; 0],
+}
 unsafe extern "C" {
     pub fn opus_strerror(error: ::std::os::raw::c_int) -> *const ::std::os::raw::c_char;
     pub fn opus_get_version_string() -> *const ::std::os::raw::c_char;
@@ -228,4 +253,171 @@ unsafe extern "C" {
         nb_streams: ::std::os::raw::c_int,
     ) -> ::std::os::raw::c_int;
     pub fn opus_multistream_packet_unpad(data: *mut ::std::os::raw::c_uchar, len: opus_int32, nb_streams: ::std::os::raw::c_int) -> opus_int32;
+    pub fn opus_multistream_encoder_get_size(streams: ::std::os::raw::c_int, coupled_streams: ::std::os::raw::c_int) -> opus_int32;
+    pub fn opus_multistream_surround_encoder_get_size(channels: ::std::os::raw::c_int, mapping_family: ::std::os::raw::c_int) -> opus_int32;
+    pub fn opus_multistream_encoder_create(
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        streams: ::std::os::raw::c_int,
+        coupled_streams: ::std::os::raw::c_int,
+        mapping: *const ::std::os::raw::c_uchar,
+        application: ::std::os::raw::c_int,
+        error: *mut ::std::os::raw::c_int,
+    ) -> *mut OpusMSEncoder;
+    pub fn opus_multistream_surround_encoder_create(
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        mapping_family: ::std::os::raw::c_int,
+        streams: *mut ::std::os::raw::c_int,
+        coupled_streams: *mut ::std::os::raw::c_int,
+        mapping: *mut ::std::os::raw::c_uchar,
+        application: ::std::os::raw::c_int,
+        error: *mut ::std::os::raw::c_int,
+    ) -> *mut OpusMSEncoder;
+    pub fn opus_multistream_encoder_init(
+        st: *mut OpusMSEncoder,
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        streams: ::std::os::raw::c_int,
+        coupled_streams: ::std::os::raw::c_int,
+        mapping: *const ::std::os::raw::c_uchar,
+        application: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_surround_encoder_init(
+        st: *mut OpusMSEncoder,
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        mapping_family: ::std::os::raw::c_int,
+        streams: *mut ::std::os::raw::c_int,
+        coupled_streams: *mut ::std::os::raw::c_int,
+        mapping: *mut ::std::os::raw::c_uchar,
+        application: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_encode(
+        st: *mut OpusMSEncoder,
+        pcm: *const opus_int16,
+        frame_size: ::std::os::raw::c_int,
+        data: *mut ::std::os::raw::c_uchar,
+        max_data_bytes: opus_int32,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_encode_float(
+        st: *mut OpusMSEncoder,
+        pcm: *const f32,
+        frame_size: ::std::os::raw::c_int,
+        data: *mut ::std::os::raw::c_uchar,
+        max_data_bytes: opus_int32,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_encoder_destroy(st: *mut OpusMSEncoder);
+    pub fn opus_multistream_encoder_ctl(st: *mut OpusMSEncoder, request: ::std::os::raw::c_int, ...) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_decoder_get_size(streams: ::std::os::raw::c_int, coupled_streams: ::std::os::raw::c_int) -> opus_int32;
+    pub fn opus_multistream_decoder_create(
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        streams: ::std::os::raw::c_int,
+        coupled_streams: ::std::os::raw::c_int,
+        mapping: *const ::std::os::raw::c_uchar,
+        error: *mut ::std::os::raw::c_int,
+    ) -> *mut OpusMSDecoder;
+    pub fn opus_multistream_decoder_init(
+        st: *mut OpusMSDecoder,
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        streams: ::std::os::raw::c_int,
+        coupled_streams: ::std::os::raw::c_int,
+        mapping: *const ::std::os::raw::c_uchar,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_decode(
+        st: *mut OpusMSDecoder,
+        data: *const ::std::os::raw::c_uchar,
+        len: opus_int32,
+        pcm: *mut opus_int16,
+        frame_size: ::std::os::raw::c_int,
+        decode_fec: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_decode_float(
+        st: *mut OpusMSDecoder,
+        data: *const ::std::os::raw::c_uchar,
+        len: opus_int32,
+        pcm: *mut f32,
+        frame_size: ::std::os::raw::c_int,
+        decode_fec: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_decoder_ctl(st: *mut OpusMSDecoder, request: ::std::os::raw::c_int, ...) -> ::std::os::raw::c_int;
+    pub fn opus_multistream_decoder_destroy(st: *mut OpusMSDecoder);
+    pub fn opus_projection_ambisonics_encoder_get_size(channels: ::std::os::raw::c_int, mapping_family: ::std::os::raw::c_int) -> opus_int32;
+    pub fn opus_projection_ambisonics_encoder_create(
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        mapping_family: ::std::os::raw::c_int,
+        streams: *mut ::std::os::raw::c_int,
+        coupled_streams: *mut ::std::os::raw::c_int,
+        application: ::std::os::raw::c_int,
+        error: *mut ::std::os::raw::c_int,
+    ) -> *mut OpusProjectionEncoder;
+    pub fn opus_projection_ambisonics_encoder_init(
+        st: *mut OpusProjectionEncoder,
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        mapping_family: ::std::os::raw::c_int,
+        streams: *mut ::std::os::raw::c_int,
+        coupled_streams: *mut ::std::os::raw::c_int,
+        application: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_projection_encode(
+        st: *mut OpusProjectionEncoder,
+        pcm: *const opus_int16,
+        frame_size: ::std::os::raw::c_int,
+        data: *mut ::std::os::raw::c_uchar,
+        max_data_bytes: opus_int32,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_projection_encode_float(
+        st: *mut OpusProjectionEncoder,
+        pcm: *const f32,
+        frame_size: ::std::os::raw::c_int,
+        data: *mut ::std::os::raw::c_uchar,
+        max_data_bytes: opus_int32,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_projection_encoder_destroy(st: *mut OpusProjectionEncoder);
+    pub fn opus_projection_encoder_ctl(st: *mut OpusProjectionEncoder, request: ::std::os::raw::c_int, ...) -> ::std::os::raw::c_int;
+    pub fn opus_projection_decoder_get_size(
+        channels: ::std::os::raw::c_int,
+        streams: ::std::os::raw::c_int,
+        coupled_streams: ::std::os::raw::c_int,
+    ) -> opus_int32;
+    pub fn opus_projection_decoder_create(
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        streams: ::std::os::raw::c_int,
+        coupled_streams: ::std::os::raw::c_int,
+        demixing_matrix: *mut ::std::os::raw::c_uchar,
+        demixing_matrix_size: opus_int32,
+        error: *mut ::std::os::raw::c_int,
+    ) -> *mut OpusProjectionDecoder;
+    pub fn opus_projection_decoder_init(
+        st: *mut OpusProjectionDecoder,
+        Fs: opus_int32,
+        channels: ::std::os::raw::c_int,
+        streams: ::std::os::raw::c_int,
+        coupled_streams: ::std::os::raw::c_int,
+        demixing_matrix: *mut ::std::os::raw::c_uchar,
+        demixing_matrix_size: opus_int32,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_projection_decode(
+        st: *mut OpusProjectionDecoder,
+        data: *const ::std::os::raw::c_uchar,
+        len: opus_int32,
+        pcm: *mut opus_int16,
+        frame_size: ::std::os::raw::c_int,
+        decode_fec: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_projection_decode_float(
+        st: *mut OpusProjectionDecoder,
+        data: *const ::std::os::raw::c_uchar,
+        len: opus_int32,
+        pcm: *mut f32,
+        frame_size: ::std::os::raw::c_int,
+        decode_fec: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+    pub fn opus_projection_decoder_ctl(st: *mut OpusProjectionDecoder, request: ::std::os::raw::c_int, ...) -> ::std::os::raw::c_int;
+    pub fn opus_projection_decoder_destroy(st: *mut OpusProjectionDecoder);
 }
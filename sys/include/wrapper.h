@@ -1 +1,3 @@
-#include <opus.h>
\ No newline at end of file
+#include <opus.h>
+#include <opus_multistream.h>
+#include <opus_projection.h>
\ No newline at end of file